@@ -0,0 +1,109 @@
+//! Route queries answered by many clients at once, against the shipped subway data.
+//! Each benchmark answers `QUERIES` route queries in total, split evenly across its
+//! clients, so the times are directly comparable: with the read-write lock more
+//! clients should finish sooner, while clients sharing an exclusive lock cannot.
+#![feature(test)]
+#![allow(unstable)]
+
+extern crate test;
+extern crate t_query;
+
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::Thread;
+
+use test::Bencher;
+
+use t_query::{find_route, format_route};
+use t_query::server::answer;
+use t_query::subway::Subway;
+use t_query::subway::data::{load_subway_data, load_transfer_data};
+use t_query::subway::route::Query;
+
+const QUERIES: usize = 64;
+
+const TRIPS: [(&'static str, &'static str); 4] = [
+    ("Alewife Station", "Wonderland Station"),
+    ("Heath Street Station", "Oak Grove Station"),
+    ("Braintree Station", "Boston College Station"),
+    ("Forest Hills Station", "Bowdoin Station"),
+];
+
+fn load() -> Subway {
+    let mut subway = Subway::new();
+    for line in ["blue", "green", "orange", "red"].iter() {
+        let path = Path::new(format!("{}.dat", line));
+        let file = io::BufferedReader::new(io::File::open(&path));
+        load_subway_data(&mut subway, file, *line).unwrap();
+    }
+    let transfers = io::BufferedReader::new(io::File::open(&Path::new("transfers.dat")));
+    load_transfer_data(&mut subway, transfers, "transfers.dat").unwrap();
+    subway
+}
+
+fn queries(subway: &Subway) -> Vec<Query> {
+    TRIPS.iter().map(|&(from, to)| {
+        Query::Route(*subway.get_station_id(from).unwrap(), *subway.get_station_id(to).unwrap())
+    }).collect()
+}
+
+/// Answers `QUERIES` route queries from `clients` clients sharing a read-write lock.
+fn bench_rwlock(b: &mut Bencher, clients: usize) {
+    let subway = load();
+    let queries = Arc::new(queries(&subway));
+    let shared_subway = Arc::new(RwLock::new(subway));
+    b.iter(|| {
+        let guards: Vec<_> = range(0, clients).map(|client| {
+            let shared_subway = shared_subway.clone();
+            let queries = queries.clone();
+            Thread::scoped(move || {
+                for i in range(0, QUERIES / clients) {
+                    let query = queries[(client + i) % queries.len()].clone();
+                    test::black_box(answer(&*shared_subway, Ok(query)));
+                }
+            })
+        }).collect();
+        for guard in guards.into_iter() { guard.join().ok().unwrap(); }
+    });
+}
+
+/// Like `bench_rwlock`, but every client takes an exclusive lock, as all used to.
+fn bench_mutex(b: &mut Bencher, clients: usize) {
+    let subway = load();
+    let queries = Arc::new(queries(&subway));
+    let shared_subway = Arc::new(Mutex::new(subway));
+    b.iter(|| {
+        let guards: Vec<_> = range(0, clients).map(|client| {
+            let shared_subway = shared_subway.clone();
+            let queries = queries.clone();
+            Thread::scoped(move || {
+                for i in range(0, QUERIES / clients) {
+                    if let Query::Route(from, to) = queries[(client + i) % queries.len()] {
+                        let subway = shared_subway.lock().unwrap();
+                        let route = find_route(&*subway, from, to).unwrap();
+                        test::black_box(format_route(&*subway, &route));
+                    }
+                }
+            })
+        }).collect();
+        for guard in guards.into_iter() { guard.join().ok().unwrap(); }
+    });
+}
+
+#[bench]
+fn rwlock_1_client(b: &mut Bencher) { bench_rwlock(b, 1); }
+
+#[bench]
+fn rwlock_8_clients(b: &mut Bencher) { bench_rwlock(b, 8); }
+
+#[bench]
+fn rwlock_32_clients(b: &mut Bencher) { bench_rwlock(b, 32); }
+
+#[bench]
+fn mutex_1_client(b: &mut Bencher) { bench_mutex(b, 1); }
+
+#[bench]
+fn mutex_8_clients(b: &mut Bencher) { bench_mutex(b, 8); }
+
+#[bench]
+fn mutex_32_clients(b: &mut Bencher) { bench_mutex(b, 32); }
@@ -1,3 +1,3 @@
 #!/bin/sh
 
-cargo run blue.dat green.dat orange.dat red.dat
+cargo run -- --transfers transfers.dat --aliases aliases.dat blue.dat green.dat orange.dat red.dat
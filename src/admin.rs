@@ -0,0 +1,137 @@
+//! Guards changes to the subway, such as disabling stations, behind an admin token
+//! configured at startup, and keeps an audit log of every change attempted.
+//!
+//! TCP clients authenticate a session with `auth TOKEN`; HTTP clients send an
+//! `Authorization: Bearer TOKEN` header with every request that changes anything. With
+//! no token configured, nobody may change anything.
+#![allow(unstable)]
+
+use std::sync::Mutex;
+
+use time;
+
+use subway::Subway;
+use subway::route::Query;
+use subway::schedule::{describe_disruption, format_time};
+
+pub struct Admin {
+    token: Option<String>,
+    log: Mutex<Box<Writer + Send>>,
+}
+
+impl Admin {
+    /// Creates a new `Admin` accepting `token`, which writes its audit log to `log`.
+    pub fn new(token: Option<String>, log: Box<Writer + Send>) -> Admin {
+        Admin { token: token.and_then(|t| if t.is_empty() { None } else { Some(t) }),
+                log: Mutex::new(log) }
+    }
+
+    /// Whether `token` is the admin token. Takes as long whichever of its characters
+    /// differ, so as not to give the token away.
+    pub fn check(&self, token: &str) -> bool {
+        match self.token {
+            Some(ref expected) => {
+                let (a, b) = (expected.as_bytes(), token.as_bytes());
+                let differences: u8 = a.iter().zip(b.iter()).fold(0, |d, (x, y)| d | (x ^ y));
+                a.len() == b.len() && differences == 0
+            },
+            None => false,
+        }
+    }
+
+    /// Records that `client` attempted `command`, and whether it was allowed.
+    pub fn audit(&self, client: &str, command: &str, allowed: bool) {
+        let entry: String = format!("{} {} {} {}\n", time::now_utc().rfc3339(), client,
+                                    if allowed { "allowed" } else { "denied" }, command);
+        let mut log = self.log.lock().unwrap();
+        if log.write_str(entry.as_slice()).is_err() || log.flush().is_err() {
+            println!("error: unable to write audit log entry: {}", entry.trim());
+        }
+    }
+}
+
+/// `query`, which changes `subway`, written out with station names for the audit log.
+pub fn describe_command(subway: &Subway, query: &Query) -> String {
+    let name = |&: id: usize| -> String {
+        format!("{} ({})", subway.get_station(id).map(|s| s.as_slice()).unwrap_or("?"), id)
+    };
+    match *query {
+        Query::Enable(stn) => format!("enable {}", name(stn)),
+        Query::Disable(stn) => format!("disable {}", name(stn)),
+        Query::DisableStations(ref stns, ref incident) => {
+            let names: Vec<String> = stns.iter().map(|stn| name(*stn)).collect();
+            match *incident {
+                Some(ref incident) => {
+                    format!("disable {} for incident {}", names.connect(", "), incident)
+                },
+                None => format!("disable {}", names.connect(", ")),
+            }
+        },
+        Query::EnableIncident(ref incident) => format!("enable incident {}", incident),
+        Query::Alias(ref alias, stn) => format!("alias {} = {}", alias, name(stn)),
+        Query::DisableSegment(a, b) => format!("disable segment {} to {}", name(a), name(b)),
+        Query::EnableSegment(a, b) => format!("enable segment {} to {}", name(a), name(b)),
+        Query::DisableBranch(ref branch) => format!("disable branch {}", branch),
+        Query::EnableBranch(ref branch) => format!("enable branch {}", branch),
+        Query::DisableLine(ref line) => format!("disable line {}", line),
+        Query::EnableLine(ref line) => format!("enable line {}", line),
+        Query::PassThrough(stn) => format!("pass through {}", name(stn)),
+        Query::StopAt(stn) => format!("stop at {}", name(stn)),
+        Query::Schedule(ref disruption) => {
+            format!("schedule {}", describe_disruption(subway, disruption))
+        },
+        Query::Unschedule(n) => match n.checked_sub(1).and_then(|i| subway.schedule.get(i)) {
+            Some(disruption) => {
+                format!("unschedule {} ({})", n, describe_disruption(subway, disruption))
+            },
+            _ => format!("unschedule {}", n),
+        },
+        Query::UnscheduleDisruption(ref disruption) => {
+            format!("unschedule {}", describe_disruption(subway, disruption))
+        },
+        Query::ListSchedule => "schedule".to_string(),
+        Query::Status => "status".to_string(),
+        Query::Route(from, to) => format!("from {} to {}", name(from), name(to)),
+        Query::RouteAt(from, to, at) => {
+            format!("from {} to {} depart at {}", name(from), name(to), format_time(at))
+        },
+    }
+}
+
+#[cfg(test)]
+mod admin_tests {
+    use std::io::{ChanReader, ChanWriter};
+    use std::sync::mpsc::channel;
+
+    use super::{Admin, describe_command};
+    use subway::Subway;
+    use subway::route::Query;
+
+    #[test]
+    fn test_check() {
+        let (send, _) = channel();
+        let admin = Admin::new(Some("s3cret".to_string()), Box::new(ChanWriter::new(send)));
+        assert!(admin.check("s3cret"));
+        assert!(!admin.check("s3cres"));
+        assert!(!admin.check("s3cret "));
+        assert!(!admin.check(""));
+
+        let (send, _) = channel();
+        let nobody = Admin::new(Some("".to_string()), Box::new(ChanWriter::new(send)));
+        assert!(!nobody.check(""));
+    }
+
+    #[test]
+    fn test_audit() {
+        let mut subway = Subway::new();
+        let a_id = subway.add_station("A");
+        let (send, recv) = channel();
+        let admin = Admin::new(None, Box::new(ChanWriter::new(send)));
+        let command = describe_command(&subway, &Query::Disable(a_id));
+        admin.audit("10.0.0.1:4000", command.as_slice(), false);
+        drop(admin);
+
+        let entry: String = ChanReader::new(recv).read_to_string().unwrap();
+        assert!(entry.as_slice().ends_with(" 10.0.0.1:4000 denied disable A (0)\n"));
+    }
+}
@@ -0,0 +1,198 @@
+//! Error type shared by every part of `t_query`.
+
+use std::error::Error;
+use std::fmt;
+
+use subway::{Subway, StationId};
+
+#[derive(Clone, PartialEq, Show)]
+pub enum TQueryError {
+    /// No station matches the text given by the user. `suggestions` are stations with
+    /// names close to it, best first.
+    NoSuchStation { query: String, suggestions: Vec<StationId> },
+    /// More than one station matches the text given by the user.
+    AmbiguousStation { query: String, candidates: Vec<StationId> },
+    /// Both stations exist but there is no way to get from one to the other.
+    NoPath { from: StationId, to: StationId },
+    /// A route would have to start or end at a station that is disabled.
+    DisabledStation { station: StationId },
+    /// The two stations named are not next to each other on any line.
+    NoSuchSegment { from: StationId, to: StationId },
+    /// No line has a branch by the name given by the user.
+    NoSuchBranch { query: String },
+    /// No line goes by the name given by the user.
+    NoSuchLine { query: String },
+    /// The given disruption, written out, is not scheduled.
+    NotScheduled { disruption: String },
+    /// `alias` already stands for another station, `station`.
+    AliasTaken { alias: String, station: StationId },
+    /// No open incident has the given name.
+    NoSuchIncident { incident: String },
+    /// A time given by the user cannot be read, or makes no sense where it is used.
+    InvalidTime { time: String, reason: String },
+    /// A `/pattern/` given to match stations by is not a valid regular expression.
+    InvalidPattern { pattern: String, reason: String },
+    /// `choose` was sent without a number naming one of the `count` candidates offered,
+    /// or with nothing to choose from if `count` is 0.
+    InvalidChoice { choice: String, count: usize },
+    /// `command` changes the subway, but the client has not authenticated as an admin.
+    Unauthorized { command: String },
+    /// The query is not one `t_query` understands.
+    ParseQuery { query: String },
+    /// A subway data file is malformed.
+    DataFormat { file: String, line: usize, reason: String },
+    /// Subway line `line` cannot be written out as a data file.
+    Unexportable { line: String, reason: String },
+}
+
+impl TQueryError {
+    /// A short, stable name for the kind of error, meant for clients rather than people.
+    pub fn code(&self) -> &'static str {
+        match *self {
+            TQueryError::NoSuchStation { .. } => "no_such_station",
+            TQueryError::AmbiguousStation { .. } => "ambiguous_station",
+            TQueryError::NoPath { .. } => "no_path",
+            TQueryError::DisabledStation { .. } => "disabled_station",
+            TQueryError::NoSuchSegment { .. } => "no_such_segment",
+            TQueryError::NoSuchBranch { .. } => "no_such_branch",
+            TQueryError::NoSuchLine { .. } => "no_such_line",
+            TQueryError::NotScheduled { .. } => "not_scheduled",
+            TQueryError::AliasTaken { .. } => "alias_taken",
+            TQueryError::NoSuchIncident { .. } => "no_such_incident",
+            TQueryError::InvalidTime { .. } => "invalid_time",
+            TQueryError::InvalidPattern { .. } => "invalid_pattern",
+            TQueryError::InvalidChoice { .. } => "invalid_choice",
+            TQueryError::Unauthorized { .. } => "unauthorized",
+            TQueryError::ParseQuery { .. } => "parse_query",
+            TQueryError::DataFormat { .. } => "data_format",
+            TQueryError::Unexportable { .. } => "unexportable",
+        }
+    }
+
+    /// Renders the error like its `String` formatting does, but with station names in
+    /// place of station ids.
+    pub fn describe(&self, subway: &Subway) -> String {
+        let name = |&: id: &StationId| -> String {
+            subway.get_station(*id).map(|s| s.clone()).unwrap_or_else(|| id.to_string())
+        };
+        match *self {
+            TQueryError::AmbiguousStation { ref candidates, .. } => {
+                let mut emsg = "disambiguate your destination:\n".to_string();
+                for (i, id) in candidates.iter().enumerate() {
+                    emsg.push_str(format!("{}. {}\n", i + 1, name(id)).as_slice());
+                }
+                emsg.push_str("reply `choose N` to pick one");
+                emsg
+            },
+            TQueryError::NoPath { ref from, ref to } => {
+                format!("No path from {} to {}", name(from), name(to))
+            },
+            TQueryError::DisabledStation { ref station } => {
+                format!("Station disabled: {}", name(station))
+            },
+            TQueryError::NoSuchSegment { ref from, ref to } => {
+                format!("No segment between {} and {}", name(from), name(to))
+            },
+            TQueryError::AliasTaken { ref alias, ref station } => {
+                format!("Alias taken: {} is already {}", alias, name(station))
+            },
+            TQueryError::NoSuchStation { ref query, ref suggestions }
+                    if !suggestions.is_empty() => {
+                let names: Vec<String> = suggestions.iter().map(|id| name(id)).collect();
+                format!("No such station: {}, did you mean {}?", query, names.connect(" or "))
+            },
+            _ => format!("{}", self),
+        }
+    }
+}
+
+impl fmt::String for TQueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TQueryError::NoSuchStation { ref query, ref suggestions } => {
+                try!(write!(f, "No such station: {}", query));
+                if suggestions.is_empty() { return Ok(()); }
+                let ids: Vec<String> = suggestions.iter().map(|id| id.to_string()).collect();
+                write!(f, ", did you mean {}?", ids.connect(" or "))
+            },
+            TQueryError::AmbiguousStation { ref candidates, .. } => {
+                try!(write!(f, "disambiguate your destination:"));
+                for id in candidates.iter() { try!(write!(f, " {}", id)); }
+                Ok(())
+            },
+            TQueryError::NoPath { ref from, ref to } => {
+                write!(f, "No path from {} to {}", from, to)
+            },
+            TQueryError::DisabledStation { ref station } => {
+                write!(f, "Station disabled: {}", station)
+            },
+            TQueryError::NoSuchSegment { ref from, ref to } => {
+                write!(f, "No segment between {} and {}", from, to)
+            },
+            TQueryError::NoSuchBranch { ref query } => write!(f, "No such branch: {}", query),
+            TQueryError::NoSuchLine { ref query } => write!(f, "No such line: {}", query),
+            TQueryError::NotScheduled { ref disruption } => {
+                write!(f, "Not scheduled: {}", disruption)
+            },
+            TQueryError::AliasTaken { ref alias, ref station } => {
+                write!(f, "Alias taken: {} is already {}", alias, station)
+            },
+            TQueryError::NoSuchIncident { ref incident } => {
+                write!(f, "No such incident: {}", incident)
+            },
+            TQueryError::InvalidTime { ref time, ref reason } => {
+                write!(f, "invalid time {}: {}", time, reason)
+            },
+            TQueryError::InvalidPattern { ref pattern, ref reason } => {
+                write!(f, "invalid station pattern /{}/: {}", pattern, reason)
+            },
+            TQueryError::InvalidChoice { ref choice, count: 0 } => {
+                write!(f, "nothing to choose from: {}", choice)
+            },
+            TQueryError::InvalidChoice { ref choice, ref count } => {
+                write!(f, "invalid choice: {}, expected a number from 1 to {}", choice, count)
+            },
+            TQueryError::Unauthorized { ref command } => {
+                write!(f, "unauthorized: `{}` needs an admin, authenticate with `auth TOKEN`",
+                       command)
+            },
+            TQueryError::ParseQuery { ref query } => {
+                write!(f, "unable to parse query: {}", query)
+            },
+            TQueryError::DataFormat { ref file, ref line, ref reason } => {
+                write!(f, "{}:{}: {}", file, line, reason)
+            },
+            TQueryError::Unexportable { ref line, ref reason } => {
+                write!(f, "cannot export line {}: {}", line, reason)
+            },
+        }
+    }
+}
+
+impl Error for TQueryError {
+    fn description(&self) -> &str {
+        match *self {
+            TQueryError::NoSuchStation { .. } => "no such station",
+            TQueryError::AmbiguousStation { .. } => "ambiguous station",
+            TQueryError::NoPath { .. } => "no path between stations",
+            TQueryError::DisabledStation { .. } => "station disabled",
+            TQueryError::NoSuchSegment { .. } => "stations not adjacent",
+            TQueryError::NoSuchBranch { .. } => "no such branch",
+            TQueryError::NoSuchLine { .. } => "no such line",
+            TQueryError::NotScheduled { .. } => "not scheduled",
+            TQueryError::AliasTaken { .. } => "alias already taken",
+            TQueryError::NoSuchIncident { .. } => "no such incident",
+            TQueryError::InvalidTime { .. } => "invalid time",
+            TQueryError::InvalidPattern { .. } => "invalid station pattern",
+            TQueryError::InvalidChoice { .. } => "invalid choice",
+            TQueryError::Unauthorized { .. } => "not authorized",
+            TQueryError::ParseQuery { .. } => "unable to parse query",
+            TQueryError::DataFormat { .. } => "malformed subway data",
+            TQueryError::Unexportable { .. } => "line cannot be exported",
+        }
+    }
+
+    fn detail(&self) -> Option<String> {
+        Some(format!("{}", self))
+    }
+}
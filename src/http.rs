@@ -0,0 +1,536 @@
+//! HTTP API for `t_query`, answering in JSON and sharing its `Subway` with the TCP
+//! server:
+//!
+//! * `GET /route?from=STATION&to=STATION` - the route between two stations, by leg
+//! * `GET /stations` - every station
+//! * `GET /lines` - the name of every line
+//! * `POST /stations/ID/disable` and `POST /stations/ID/enable`, which need an
+//!   `Authorization: Bearer TOKEN` header with the admin token, see `admin`
+//!
+//! Stations are given as `{"id": 3, "name": "Park Street", "label": "Park Street"}`.
+//! Errors are given as `{"error": {"kind": "no_such_station", "message": "..."}}`,
+//! where `kind` is `TQueryError::code` or one of `bad_request`, `missing_parameter`,
+//! `not_found` and `method_not_allowed`. Ambiguous and unknown stations also list `candidates` and
+//! `suggestions` respectively.
+#![allow(unstable)]
+
+use std::ascii::AsciiExt;
+use std::collections::BTreeMap;
+use std::num;
+use std::str;
+use std::io::{TcpListener, TcpStream, BufferedStream, Listener, Acceptor};
+use std::io::net::tcp::TcpAcceptor;
+use std::sync::{Arc, RwLock};
+use std::thread::Thread;
+
+use rustc_serialize::json::{self, Json};
+
+use error::TQueryError;
+use subway::{Subway, StationId};
+use subway::route::{Route, Query, find_route, route_warnings};
+use subway::schedule::format_time;
+use admin::{Admin, describe_command};
+
+// chosen arbitrarily
+const MAX_REQUEST_LINE_LENGTH: usize = 8192;
+const MAX_BODY_LENGTH: usize = 65536;
+
+type Object = BTreeMap<String, Json>;
+
+/// A reply to an HTTP request: its status code and JSON body.
+#[derive(PartialEq, Show)]
+pub struct Response {
+    pub status: u16,
+    pub body: Json,
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        401 => "Unauthorized",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        _ => "Internal Server Error",
+    }
+}
+
+fn object(fields: Vec<(&str, Json)>) -> Json {
+    Json::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect::<Object>())
+}
+
+fn station_json(subway: &Subway, stn_id: StationId) -> Json {
+    object(vec![("id", Json::U64(stn_id as u64)),
+                ("name", Json::String(subway.names[stn_id].clone())),
+                ("label", Json::String(subway.stations[stn_id].clone()))])
+}
+
+/// Like `station_json`, along with whether the station is disabled.
+fn station_state_json(subway: &Subway, stn_id: StationId) -> Json {
+    let mut station = station_json(subway, stn_id);
+    if let Json::Object(ref mut fields) = station {
+        fields.insert("disabled".to_string(),
+                      Json::Boolean(subway.disabled_stations.contains(&stn_id)));
+        if let Some(since) = subway.disabled_since.get(&stn_id) {
+            fields.insert("disabled_since".to_string(), Json::String(format_time(*since)));
+        }
+    }
+    station
+}
+
+fn route_json(subway: &Subway, route: &Route) -> Json {
+    let legs: Vec<Json> = route.legs.iter().map(|leg| {
+        object(vec![("line", Json::String(leg.info.line.clone())),
+                    ("branch", Json::String(leg.info.branch.clone())),
+                    ("board", station_json(subway, leg.board)),
+                    ("alight", station_json(subway, leg.alight)),
+                    ("stops", Json::Array(leg.stops.iter().map(|id| station_json(subway, *id))
+                                                          .collect()))])
+    }).collect();
+    object(vec![("legs", Json::Array(legs)),
+                ("cost", Json::U64(route.cost as u64)),
+                ("transfers", Json::U64(route.transfers as u64)),
+                ("minutes", route.minutes.map(|m| Json::U64(m as u64)).unwrap_or(Json::Null)),
+                ("disabled", Json::Array(route.disabled.iter()
+                                                       .map(|id| station_json(subway, *id))
+                                                       .collect())),
+                ("warnings", Json::Array(route_warnings(subway, route).into_iter()
+                                                                     .map(Json::String)
+                                                                     .collect()))])
+}
+
+fn error_response(status: u16, kind: &str, message: String) -> Response {
+    Response {
+        status: status,
+        body: object(vec![("error", object(vec![("kind", Json::String(kind.to_string())),
+                                                ("message", Json::String(message))]))]),
+    }
+}
+
+fn query_error(subway: &Subway, e: TQueryError) -> Response {
+    let status: u16 = match e {
+        TQueryError::NoSuchStation { .. } | TQueryError::NoPath { .. } |
+            TQueryError::DisabledStation { .. } => 404,
+        TQueryError::AmbiguousStation { .. } => 409,
+        TQueryError::Unauthorized { .. } => 401,
+        _ => 400,
+    };
+    let stations = |&: ids: &Vec<StationId>| -> Json {
+        Json::Array(ids.iter().map(|id| station_json(subway, *id)).collect())
+    };
+    let mut error = vec![("kind", Json::String(e.code().to_string())),
+                         ("message", Json::String(e.describe(subway)))];
+    match e {
+        TQueryError::AmbiguousStation { ref candidates, .. } => {
+            error.push(("candidates", stations(candidates)));
+        },
+        TQueryError::NoSuchStation { ref suggestions, .. } => {
+            error.push(("suggestions", stations(suggestions)));
+        },
+        _ => {},
+    }
+    Response { status: status, body: object(vec![("error", object(error))]) }
+}
+
+fn ok(body: Json) -> Response {
+    Response { status: 200, body: body }
+}
+
+/// Decodes a `%`-escaped URL component, in which `+` also stands for a space.
+fn percent_decode(s: &str) -> String {
+    let bytes: &[u8] = s.as_bytes();
+    let mut decoded: Vec<u8> = vec![];
+    let mut i: usize = 0;
+    while i < bytes.len() {
+        let hex: Option<u8> = if bytes[i] == b'%' && i + 2 < bytes.len() {
+            str::from_utf8(bytes.slice(i + 1, i + 3)).ok()
+                                                     .and_then(|h| num::from_str_radix(h, 16))
+        } else {
+            None
+        };
+        match hex {
+            Some(b) => { decoded.push(b); i += 3; },
+            None => {
+                decoded.push(if bytes[i] == b'+' { b' ' } else { bytes[i] });
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(decoded.as_slice()).to_string()
+}
+
+/// Splits a request target into its path and its decoded query parameters.
+fn parse_target(target: &str) -> (String, BTreeMap<String, String>) {
+    let (path, query) = match target.find('?') {
+        Some(q) => (target.slice_to(q), target.slice_from(q + 1)),
+        None => (target, ""),
+    };
+    let mut params: BTreeMap<String, String> = BTreeMap::new();
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = match pair.find('=') {
+            Some(eq) => (pair.slice_to(eq), pair.slice_from(eq + 1)),
+            None => (pair, ""),
+        };
+        params.insert(percent_decode(key), percent_decode(value));
+    }
+    (percent_decode(path), params)
+}
+
+/// The resources served.
+enum Endpoint<'a> {
+    Route,
+    Stations,
+    Lines,
+    /// `stations/ID/enable` when `true`, `stations/ID/disable` when `false`
+    SetState(&'a str, bool),
+}
+
+impl<'a> Endpoint<'a> {
+    fn from_path(segments: &[&'a str]) -> Option<Endpoint<'a>> {
+        match (segments.len(), segments.get(0).map(|s| *s)) {
+            (1, Some("route")) => Some(Endpoint::Route),
+            (1, Some("stations")) => Some(Endpoint::Stations),
+            (1, Some("lines")) => Some(Endpoint::Lines),
+            (3, Some("stations")) if segments[2] == "enable" || segments[2] == "disable" => {
+                Some(Endpoint::SetState(segments[1], segments[2] == "enable"))
+            },
+            _ => None,
+        }
+    }
+
+    fn method(&self) -> &'static str {
+        match *self {
+            Endpoint::SetState(..) => "POST",
+            _ => "GET",
+        }
+    }
+}
+
+/// Answers the request `method target` from `client` against the shared subway.
+/// `bearer` is the token the request was authorized with, if any.
+pub fn respond(shared_subway: &RwLock<Subway>, admin: &Admin, client: &str, method: &str,
+               target: &str, bearer: Option<&str>) -> Response {
+    let (path, params) = parse_target(target);
+    let segments: Vec<&str> = path.as_slice().split('/').filter(|s| !s.is_empty()).collect();
+
+    let endpoint: Endpoint = match Endpoint::from_path(segments.as_slice()) {
+        Some(endpoint) => endpoint,
+        None => return error_response(404, "not_found", format!("no such resource: {}", path)),
+    };
+    if method != endpoint.method() {
+        return error_response(405, "method_not_allowed",
+                              format!("{} {} is not supported", method, path));
+    }
+
+    match endpoint {
+        Endpoint::Route => {
+            for name in ["from", "to"].iter() {
+                if params.get(*name).map(|p| p.is_empty()).unwrap_or(true) {
+                    return error_response(400, "missing_parameter",
+                                          format!("missing parameter: {}", name));
+                }
+            }
+            let subway = shared_subway.read().unwrap();
+            let from: &str = params.get("from").unwrap().as_slice();
+            let to: &str = params.get("to").unwrap().as_slice();
+            let found = subway.find_station(from).and_then(|from_id| {
+                subway.find_station(to).and_then(|to_id| find_route(&*subway, from_id, to_id))
+            });
+            match found {
+                Ok(route) => ok(route_json(&*subway, &route)),
+                Err(e) => query_error(&*subway, e),
+            }
+        },
+        Endpoint::Stations => {
+            let subway = shared_subway.read().unwrap();
+            // merged stations are listed once, as the station they were merged into
+            let stations: Vec<Json> = range(0, subway.size()).filter(|id| subway.is_station(*id))
+                .map(|id| station_state_json(&*subway, id)).collect();
+            ok(Json::Array(stations))
+        },
+        Endpoint::Lines => {
+            let subway = shared_subway.read().unwrap();
+            ok(Json::Array(subway.lines().into_iter().map(|l| Json::String(l)).collect()))
+        },
+        Endpoint::SetState(id, enable) => {
+            let authorized: bool = bearer.map(|token| admin.check(token)).unwrap_or(false);
+            let (stn_id, query, command) = {
+                let subway = shared_subway.read().unwrap();
+                let stn_id: StationId = match id.parse::<usize>() {
+                    Some(stn_id) if subway.is_station(stn_id) => stn_id,
+                    _ => {
+                        let e = TQueryError::NoSuchStation { query: id.to_string(),
+                                                             suggestions: vec![] };
+                        return query_error(&*subway, e);
+                    },
+                };
+                let query = if enable { Query::Enable(stn_id) } else { Query::Disable(stn_id) };
+                let command: String = describe_command(&*subway, &query);
+                (stn_id, query, command)
+            };
+            // audited holding no lock, so writing the log holds up no other request
+            admin.audit(client, command.as_slice(), authorized);
+            if !authorized {
+                let e = TQueryError::Unauthorized { command: command };
+                return query_error(&*shared_subway.read().unwrap(), e);
+            }
+            let mut subway = shared_subway.write().unwrap();
+            if let Err(e) = subway.apply(&query) { return query_error(&*subway, e); }
+            ok(station_state_json(&*subway, stn_id))
+        },
+    }
+}
+
+/// A request as read off the wire: its method, target and any bearer token.
+struct Request {
+    method: String,
+    target: String,
+    bearer: Option<String>,
+}
+
+/// Reads the next line off `stream`, without its terminator, holding no more than
+/// `MAX_REQUEST_LINE_LENGTH` bytes of it at a time. `None` if the line is longer than
+/// that or the client disconnected first.
+fn read_line<B: Buffer>(stream: &mut B) -> Option<String> {
+    let mut line: Vec<u8> = vec![];
+    loop {
+        let (used, done) = match stream.fill_buf() {
+            Ok(available) => {
+                let end: Option<usize> = available.iter().position(|b| *b == b'\n');
+                let length: usize = end.unwrap_or(available.len());
+                if line.len() + length > MAX_REQUEST_LINE_LENGTH { return None; }
+                line.push_all(available.slice_to(length));
+                (end.map(|e| e + 1).unwrap_or(length), end.is_some())
+            },
+            Err(_) => return None,
+        };
+        stream.consume(used);
+        if done { break; }
+    }
+    String::from_utf8(line).ok()
+}
+
+/// Reads a request off `stream`, or `None` if it is not a request this server
+/// understands.
+fn read_request<B: Buffer>(stream: &mut B) -> Option<Request> {
+    let request_line: String = match read_line(stream) {
+        Some(line) => line,
+        None => return None,
+    };
+    let parts: Vec<&str> = request_line.as_slice().trim().split(' ').collect();
+    if parts.len() != 3 || !parts[2].starts_with("HTTP/") { return None; }
+
+    let mut body_length: usize = 0;
+    let mut bearer: Option<String> = None;
+    loop {
+        let header: String = match read_line(stream) {
+            Some(header) => header,
+            None => return None,
+        };
+        let header: &str = header.as_slice().trim();
+        if header.is_empty() { break; }
+        if let Some(colon) = header.find(':') {
+            let name: &str = header.slice_to(colon).trim();
+            let value: &str = header.slice_from(colon + 1).trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                body_length = match value.parse::<usize>() {
+                    Some(length) => length,
+                    None => return None,
+                };
+            } else if name.eq_ignore_ascii_case("authorization") && value.starts_with("Bearer ") {
+                bearer = Some(value.slice_from("Bearer ".len()).trim().to_string());
+            }
+        }
+    }
+    // requests carry everything in their target, any body is skipped
+    if body_length > MAX_BODY_LENGTH || stream.read_exact(body_length).is_err() { return None; }
+    Some(Request { method: parts[0].to_string(), target: parts[1].to_string(), bearer: bearer })
+}
+
+fn write_response(stream: &mut BufferedStream<TcpStream>, response: &Response) {
+    let body: String = json::encode(&response.body);
+    let head: String = format!("HTTP/1.1 {} {}\r\nContent-Type: application/json\r\n\
+                                Content-Length: {}\r\nConnection: close\r\n\r\n",
+                               response.status, reason_phrase(response.status), body.len());
+    let written = stream.write_str(head.as_slice()).is_ok() &&
+                  stream.write_str(body.as_slice()).is_ok() &&
+                  stream.flush().is_ok();
+    if !written { println!("error: unable to send HTTP response"); }
+}
+
+/// Serves the HTTP API on `bind_addr`, one request per connection.
+pub fn start(bind_addr: &str, shared_subway: Arc<RwLock<Subway>>, admin: Arc<Admin>) {
+    let listener: TcpListener = TcpListener::bind(bind_addr).unwrap();
+    let mut acceptor: TcpAcceptor = listener.listen().unwrap();
+    for stream in acceptor.incoming() {
+        match stream {
+            Err(e) => { println!("error: {}", e) }
+            Ok(mut stream) => {
+                let client: String = stream.peer_name().map(|addr| format!("{}", addr))
+                                                       .unwrap_or("unknown".to_string());
+                let shared_subway = shared_subway.clone();
+                let admin = admin.clone();
+                Thread::spawn(move || {
+                    let mut stream: BufferedStream<TcpStream> = BufferedStream::new(stream);
+                    let response: Response = match read_request(&mut stream) {
+                        Some(request) => {
+                            println!("http: {} {}", request.method, request.target);
+                            respond(&*shared_subway, &*admin, client.as_slice(),
+                                    request.method.as_slice(), request.target.as_slice(),
+                                    request.bearer.as_ref().map(|t| t.as_slice()))
+                        },
+                        None => error_response(400, "bad_request",
+                                               "malformed HTTP request".to_string()),
+                    };
+                    write_response(&mut stream, &response);
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod http_tests {
+    use std::sync::RwLock;
+
+    use rustc_serialize::json::Json;
+
+    use std::io::{BufferedReader, ChanWriter, MemReader};
+    use std::sync::mpsc::channel;
+
+    use super::{respond, parse_target, read_request, Response, MAX_REQUEST_LINE_LENGTH};
+    use admin::Admin;
+    use subway::Subway;
+
+    fn sample() -> RwLock<Subway> {
+        let mut subway = Subway::new();
+        let a_id = subway.add_station("Park Street");
+        let b_id = subway.add_station("Downtown Crossing");
+        let c_id = subway.add_station("South Station");
+        for &(f, t, line) in [(a_id, b_id, "red"), (b_id, c_id, "red")].iter() {
+            subway.add_connection(f, t, line, line);
+            subway.add_connection(t, f, line, line);
+        }
+        subway.add_station("Park Place");
+        RwLock::new(subway)
+    }
+
+    /// Sends a request to the API with the admin token `s3cret`.
+    fn request(subway: &RwLock<Subway>, method: &str, target: &str, bearer: Option<&str>)
+               -> Response {
+        let (send, _log) = channel();
+        let admin = Admin::new(Some("s3cret".to_string()), Box::new(ChanWriter::new(send)));
+        respond(subway, &admin, "client", method, target, bearer)
+    }
+
+    fn kind(body: &Json) -> Option<&str> {
+        body.find_path(&["error", "kind"]).and_then(|k| k.as_string())
+    }
+
+    #[test]
+    fn test_read_request() {
+        let request = "GET /lines HTTP/1.1\r\nAuthorization: Bearer s3cret\r\n\r\n";
+        let mut stream = BufferedReader::new(MemReader::new(request.as_bytes().to_vec()));
+        let read = read_request(&mut stream).unwrap();
+        assert_eq!((read.method.as_slice(), read.target.as_slice()), ("GET", "/lines"));
+        assert_eq!(read.bearer, Some("s3cret".to_string()));
+
+        // lines too long are given up on, whether they end or not
+        let long: String = range(0, MAX_REQUEST_LINE_LENGTH).map(|_| 'a').collect();
+        let request = format!("GET /{} HTTP/1.1\r\n\r\n", long);
+        let mut stream = BufferedReader::with_capacity(64, MemReader::new(request.into_bytes()));
+        assert!(read_request(&mut stream).is_none());
+        let header = format!("GET /lines HTTP/1.1\r\nX-Padding: {}", long);
+        let mut stream = BufferedReader::with_capacity(64, MemReader::new(header.into_bytes()));
+        assert!(read_request(&mut stream).is_none());
+
+        let request = "POST /stations/1/disable HTTP/1.1\r\nContent-Length: lots\r\n\r\n";
+        let mut stream = BufferedReader::new(MemReader::new(request.as_bytes().to_vec()));
+        assert!(read_request(&mut stream).is_none());
+    }
+
+    #[test]
+    fn test_parse_target() {
+        let (path, params) = parse_target("/route?from=Park+Street&to=South%20Station&x");
+        assert_eq!(path.as_slice(), "/route");
+        assert_eq!(params.get("from").map(|s| s.as_slice()), Some("Park Street"));
+        assert_eq!(params.get("to").map(|s| s.as_slice()), Some("South Station"));
+        assert_eq!(params.get("x").map(|s| s.as_slice()), Some(""));
+        assert_eq!(parse_target("/a%2").0.as_slice(), "/a%2");
+    }
+
+    #[test]
+    fn test_route() {
+        let subway = sample();
+        let response = request(&subway, "GET", "/route?from=park+street&to=south", None);
+        assert_eq!(response.status, 200);
+        let legs = response.body.find("legs").and_then(|l| l.as_array()).unwrap();
+        assert_eq!(legs.len(), 1);
+        assert_eq!(legs[0].find_path(&["alight", "name"]).and_then(|n| n.as_string()),
+                   Some("South Station"));
+        assert_eq!(legs[0].find("stops").and_then(|s| s.as_array()).map(|s| s.len()), Some(1));
+
+        let ambiguous = request(&subway, "GET", "/route?from=park&to=south", None);
+        assert_eq!(ambiguous.status, 409);
+        assert_eq!(kind(&ambiguous.body), Some("ambiguous_station"));
+        let candidates = ambiguous.body.find_path(&["error", "candidates"]).unwrap();
+        assert_eq!(candidates.as_array().map(|c| c.len()), Some(2));
+
+        let missing = request(&subway, "GET", "/route?from=park+street", None);
+        assert_eq!((missing.status, kind(&missing.body)), (400, Some("missing_parameter")));
+        let empty = request(&subway, "GET", "/route?from=&to=south", None);
+        assert_eq!((empty.status, kind(&empty.body)), (400, Some("missing_parameter")));
+        let no_path = request(&subway, "GET", "/route?from=park+street&to=park+place", None);
+        assert_eq!((no_path.status, kind(&no_path.body)), (404, Some("no_path")));
+    }
+
+    #[test]
+    fn test_stations_and_lines() {
+        let subway = sample();
+        let stations = request(&subway, "GET", "/stations", None);
+        assert_eq!(stations.body.as_array().map(|s| s.len()), Some(4));
+        let lines = request(&subway, "GET", "/lines", None);
+        assert_eq!(lines.body, Json::Array(vec![Json::String("red".to_string())]));
+    }
+
+    #[test]
+    fn test_disable_enable() {
+        let subway = sample();
+        let unauthorized = request(&subway, "POST", "/stations/1/disable", None);
+        assert_eq!((unauthorized.status, kind(&unauthorized.body)), (401, Some("unauthorized")));
+        let wrong_token = request(&subway, "POST", "/stations/1/disable", Some("guess"));
+        assert_eq!(wrong_token.status, 401);
+        {
+            // turned away without waiting for those reading the subway to finish
+            let _reading = subway.read().unwrap();
+            assert_eq!(request(&subway, "POST", "/stations/1/disable", None).status, 401);
+        }
+        assert!(subway.read().unwrap().disabled_stations.is_empty());
+
+        let disabled = request(&subway, "POST", "/stations/1/disable", Some("s3cret"));
+        assert_eq!(disabled.status, 200);
+        assert!(subway.read().unwrap().disabled_stations.contains(&1));
+        assert_eq!(disabled.body.find("disabled"), Some(&Json::Boolean(true)));
+
+        request(&subway, "POST", "/stations/1/enable", Some("s3cret"));
+        assert!(subway.read().unwrap().disabled_stations.is_empty());
+
+        let missing = request(&subway, "POST", "/stations/9/disable", None);
+        assert_eq!((missing.status, kind(&missing.body)), (404, Some("no_such_station")));
+        let merged: usize = {
+            let mut subway = subway.write().unwrap();
+            let merged = subway.add_station("Merged");
+            subway.add_transfer(0, merged, None, 0);
+            merged
+        };
+        let target: String = format!("/stations/{}/disable", merged);
+        let zombie = request(&subway, "POST", target.as_slice(), Some("s3cret"));
+        assert_eq!((zombie.status, kind(&zombie.body)), (404, Some("no_such_station")));
+        let wrong_method = request(&subway, "GET", "/stations/1/disable", None);
+        assert_eq!((wrong_method.status, kind(&wrong_method.body)),
+                   (405, Some("method_not_allowed")));
+        let unknown = request(&subway, "GET", "/nowhere", None);
+        assert_eq!((unknown.status, kind(&unknown.body)), (404, Some("not_found")));
+    }
+}
@@ -4,12 +4,25 @@
 #[plugin]
 extern crate regex_macros;
 extern crate regex;
+extern crate "rustc-serialize" as rustc_serialize;
+extern crate toml;
+extern crate time;
+
+pub use error::TQueryError;
 
 pub use subway::data::load_subway_data;
+pub use subway::gtfs::load_gtfs;
+pub use subway::network::{load_json, load_toml, export_json, export_toml};
 
 pub use subway::route::find_route;
+pub use subway::route::format_route;
+pub use subway::route::{Route, Leg};
 pub use subway::route::Query;
-pub use subway::route::Query::{Route, Enable, Disable};
+pub use subway::route::Query::{Enable, Disable, Alias};
 
+pub mod admin;
+pub mod error;
+pub mod http;
 pub mod subway;
 pub mod server;
+pub mod session;
@@ -1,11 +1,70 @@
 //! t_query manages a pseudo-MBTA subway system and has three distinct tasks:
 //!     * respond to queries by T riders on how to get from one station to another
-//!         - query format: `from STATION to STATION'
-//!             * `STATION' uniquely identifies a subway station
+//!         - query format: `from STATION to STATION [depart at YYYY-MM-DD HH:MM]'
+//!           (times are UTC), as of now unless a departure time is given
+//!             * `STATION' uniquely identifies a subway station, ignoring case,
+//!               punctuation and any "Station" suffix; the start or any part of a name
+//!               will do, and close misspellings get suggestions
+//!     * authenticate as an admin, needed for any query changing the subway
+//!         - query format: `auth TOKEN'
 //!     * disable station
 //!         - query format: `disable STATION'. See above note regarding `STATION'
 //!     * enable station, opposite of previous task
 //!         - query format: `enable STATION'
+//!     * disable several stations at once, all or none of them, for a named incident if
+//!       given, then reopen exactly the stations that incident disabled
+//!         - query format: `disable STATION, STATION ... [for incident NAME]',
+//!           `enable incident NAME'
+//!     * close or reopen the segment between two adjacent stations
+//!         - query format: `disable segment STATION to STATION', `enable segment ...'
+//!     * suspend or restore a whole branch or line
+//!         - query format: `disable branch NAME', `disable line NAME', `enable ...'
+//!     * have trains run through a station without stopping, or stop there again
+//!         - query format: `pass through STATION', `stop at STATION'
+//!     * plan a disruption ahead, list those planned, or call one off
+//!         - query format: `schedule CLOSURE from TIME until TIME', where `CLOSURE' is
+//!           `station STATION', `segment STATION to STATION', `branch NAME' or
+//!           `line NAME'; `schedule'; `unschedule N' or `unschedule CLOSURE from TIME
+//!           until TIME'
+//!     * when a `STATION' matches several stations, the reply numbers them for the
+//!       client to pick one
+//!         - query format: `choose N'
+//!     * let a station be found by another name
+//!         - query format: `alias NAME = STATION'
+//!     * list the disabled stations and when each was disabled
+//!         - query format: `status'
+//!
+//! Clients send one query per line and may keep the connection open for as many queries
+//! as they like. Every reply ends with a line reading `END'.
+//!
+//! usage: `t_query [--transfers FILE] [--aliases FILE] [--coords FILE] [--schedule FILE]
+//!                 [--gtfs DIR]
+//!                 [--export FILE] [--export-dat DIR] [--dot SCOPE] [--geojson SCOPE]
+//!                 [--http ADDR] [--admin-token TOKEN] [--audit-log FILE]
+//!                 [--disabled-policy POLICY] [--journal FILE] [LINE.dat ...]'
+//!     * `--aliases FILE' loads other names for stations from `FILE'
+//!     * `--coords FILE' loads station locations from `FILE'
+//!     * `--schedule FILE' loads disruptions planned ahead from `FILE'
+//!     * `--gtfs DIR' loads a GTFS static feed from `DIR' in addition to any line files
+//!     * line files ending in `.json' or `.toml' are whole network descriptions
+//!     * `--export FILE' writes the loaded network to `FILE' as JSON or TOML, by its
+//!       extension, and exits
+//!     * `--export-dat DIR' writes every loaded line to `DIR/LINE.dat' and exits
+//!     * `--dot SCOPE' prints a Graphviz DOT graph and exits; `SCOPE' is `all', a line
+//!       name, or a route query such as `from Kenmore to Park Street'
+//!     * `--geojson SCOPE' prints GeoJSON and exits; `SCOPE' is `all' or a route query
+//!     * `--http ADDR' also serves the HTTP/JSON API described in `http.rs' on `ADDR',
+//!       e.g. `127.0.0.1:8080'
+//!     * `--admin-token TOKEN' is the token admins authenticate with; it may instead be
+//!       given in the `T_QUERY_ADMIN_TOKEN' environment variable. Without one, nobody
+//!       may change the subway
+//!     * `--audit-log FILE' appends a line to `FILE' for every change to the subway
+//!       attempted, rather than printing it
+//!     * `--disabled-policy POLICY' is how routes treat disabled stations: `forbid' them
+//!       (the default), `penalize' them, or `penalize:COST' for a cost other than 100,
+//!       or let trains `pass-through' them without stopping
+//!     * `--journal FILE' replays the changes recorded in `FILE', if it exists, once
+//!       every other file is loaded, then records every change made while running to it
 //! ---------------------------------------------------------------------------------------------
 
 #![allow(unstable)]
@@ -15,39 +74,308 @@
 extern crate t_query;
 
 use std::io;
+use std::io::fs::PathExtensions;
 use std::os;
 
 use t_query::subway::Subway;
-use t_query::load_subway_data;
+use t_query::subway::data::{load_subway_data_checked, load_transfer_data};
+use t_query::subway::data::{load_alias_data, load_coordinate_data, load_schedule_data};
+use t_query::subway::data::{unlinked_station_warnings, export_subway_line};
+use t_query::load_gtfs;
+use t_query::{load_json, load_toml, export_json, export_toml};
 use t_query::find_route;
+use t_query::Query;
+use t_query::subway::dot::{export_dot, DotScope};
+use t_query::subway::geojson::{export_geojson, export_route_geojson};
+use t_query::subway::route::{Route, DisabledPolicy, find_route_at};
+use t_query::TQueryError;
+use t_query::admin::Admin;
+use t_query::subway::journal::{Journal, replay};
 
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, RwLock};
+use std::thread::Thread;
 
 const BIND_ADDR: &'static str = "127.0.0.1:12345";
 
+/// The route asked for by a `from STATION to STATION' query given on the command line.
+fn route_for(subway: &Subway, query: &str) -> Result<Route, TQueryError> {
+    Query::new(subway, query).and_then(|q| match q {
+        Query::Route(from, to) => find_route(subway, from, to),
+        Query::RouteAt(from, to, at) => find_route_at(subway, from, to, at),
+        _ => Err(TQueryError::ParseQuery { query: query.to_string() }),
+    })
+}
+
 fn main() {
     let args: Vec<String> = os::args();
-    let args: &[String] = args.tail();
+    let mut transfers_file: Option<String> = None;
+    let mut aliases_file: Option<String> = None;
+    let mut coords_file: Option<String> = None;
+    let mut schedule_file: Option<String> = None;
+    let mut gtfs_dir: Option<String> = None;
+    let mut export_file: Option<String> = None;
+    let mut export_dat_dir: Option<String> = None;
+    let mut dot_scope: Option<String> = None;
+    let mut geojson_scope: Option<String> = None;
+    let mut http_addr: Option<String> = None;
+    let mut admin_token: Option<String> = os::getenv("T_QUERY_ADMIN_TOKEN");
+    let mut audit_log: Option<String> = None;
+    let mut disabled_policy: DisabledPolicy = DisabledPolicy::Forbid;
+    let mut journal_file: Option<String> = None;
+    let mut line_files: Vec<String> = vec![];
 
-    if args.len() == 0 {
+    let mut args = args.into_iter().skip(1);
+    while let Some(arg) = args.next() {
+        if arg.as_slice() == "--transfers" {
+            transfers_file = args.next();
+        } else if arg.as_slice() == "--aliases" {
+            aliases_file = args.next();
+        } else if arg.as_slice() == "--coords" {
+            coords_file = args.next();
+        } else if arg.as_slice() == "--schedule" {
+            schedule_file = args.next();
+        } else if arg.as_slice() == "--gtfs" {
+            gtfs_dir = args.next();
+        } else if arg.as_slice() == "--export" {
+            export_file = args.next();
+        } else if arg.as_slice() == "--export-dat" {
+            export_dat_dir = args.next();
+        } else if arg.as_slice() == "--dot" {
+            dot_scope = args.next();
+        } else if arg.as_slice() == "--geojson" {
+            geojson_scope = args.next();
+        } else if arg.as_slice() == "--http" {
+            http_addr = args.next();
+        } else if arg.as_slice() == "--admin-token" {
+            admin_token = args.next();
+        } else if arg.as_slice() == "--audit-log" {
+            audit_log = args.next();
+        } else if arg.as_slice() == "--journal" {
+            journal_file = args.next();
+        } else if arg.as_slice() == "--disabled-policy" {
+            match args.next().and_then(|p| p.parse::<DisabledPolicy>()) {
+                Some(policy) => disabled_policy = policy,
+                None => {
+                    println!("ERROR: --disabled-policy must be forbid, penalize, penalize:COST \
+                              or pass-through");
+                    os::set_exit_status(1);
+                    return;
+                },
+            }
+        } else {
+            line_files.push(arg);
+        }
+    }
+
+    if line_files.len() == 0 && gtfs_dir.is_none() {
         println!("ERROR: Must provide at least one subway data file!");
         return;
     }
 
     let mut subway: Subway = Subway::new();
+    subway.disabled_policy = disabled_policy;
 
-    for arg in args.iter() {
+    if let Some(ref dir) = gtfs_dir {
+        if let Err(e) = load_gtfs(&mut subway, &Path::new(dir)) {
+            println!("ERROR: {}", e);
+            os::set_exit_status(1);
+            return;
+        }
+    }
+
+    for arg in line_files.iter() {
         let path: Path = Path::new(arg);
-        if let Some(subway_line) = path.filestem_str() {
+        let extension: Option<&str> = path.extension_str();
+        if extension == Some("json") || extension == Some("toml") {
+            let file_name = format!("{}", path.display());
+            let content = io::File::open(&path).read_to_string().unwrap_or_else(|e| {
+                panic!("unable to read {}: {}", file_name, e)
+            });
+            let loaded = if extension == Some("json") {
+                load_json(&mut subway, content.as_slice(), file_name.as_slice())
+            } else {
+                load_toml(&mut subway, content.as_slice(), file_name.as_slice())
+            };
+            if let Err(e) = loaded {
+                println!("ERROR: {}", e);
+                os::set_exit_status(1);
+                return;
+            }
+        } else if let Some(subway_line) = path.filestem_str() {
             let file = io::File::open(&path);
             let file_buf = io::BufferedReader::new(file);
-            load_subway_data(&mut subway, file_buf, subway_line).unwrap_or_else(|s| panic!(s));
+            let file_name = format!("{}", path.display());
+            let loaded = load_subway_data_checked(&mut subway, file_buf, subway_line,
+                                                  file_name.as_slice());
+            if let Err(problems) = loaded {
+                for problem in problems.iter() { println!("ERROR: {}", problem); }
+                os::set_exit_status(1);
+                return;
+            }
         } else {
             println!("Error getting filename from: {:?}", path);
             continue;
         }
     }
 
-    let shared_subway = Arc::new(Mutex::new(subway));
-    t_query::server::start(BIND_ADDR, shared_subway.clone());
+    if let Some(ref arg) = transfers_file {
+        let path: Path = Path::new(arg);
+        let file_buf = io::BufferedReader::new(io::File::open(&path));
+        let file_name = format!("{}", path.display());
+        if let Err(problems) = load_transfer_data(&mut subway, file_buf, file_name.as_slice()) {
+            for problem in problems.iter() { println!("ERROR: {}", problem); }
+            os::set_exit_status(1);
+            return;
+        }
+    }
+    if let Some(ref arg) = aliases_file {
+        let path: Path = Path::new(arg);
+        let file_buf = io::BufferedReader::new(io::File::open(&path));
+        let file_name = format!("{}", path.display());
+        if let Err(problems) = load_alias_data(&mut subway, file_buf, file_name.as_slice()) {
+            for problem in problems.iter() { println!("ERROR: {}", problem); }
+            os::set_exit_status(1);
+            return;
+        }
+    }
+    if let Some(ref arg) = coords_file {
+        let path: Path = Path::new(arg);
+        let file_buf = io::BufferedReader::new(io::File::open(&path));
+        let file_name = format!("{}", path.display());
+        if let Err(problems) = load_coordinate_data(&mut subway, file_buf, file_name.as_slice()) {
+            for problem in problems.iter() { println!("ERROR: {}", problem); }
+            os::set_exit_status(1);
+            return;
+        }
+    }
+    if let Some(ref arg) = schedule_file {
+        let path: Path = Path::new(arg);
+        let file_buf = io::BufferedReader::new(io::File::open(&path));
+        let file_name = format!("{}", path.display());
+        if let Err(problems) = load_schedule_data(&mut subway, file_buf, file_name.as_slice()) {
+            for problem in problems.iter() { println!("ERROR: {}", problem); }
+            os::set_exit_status(1);
+            return;
+        }
+    }
+    if let Some(ref arg) = journal_file {
+        let path: Path = Path::new(arg);
+        if path.exists() {
+            let file_buf = io::BufferedReader::new(io::File::open(&path));
+            let file_name = format!("{}", path.display());
+            if let Err(problems) = replay(&mut subway, file_buf, file_name.as_slice()) {
+                for problem in problems.iter() { println!("ERROR: {}", problem); }
+                os::set_exit_status(1);
+                return;
+            }
+        }
+    }
+    for warning in unlinked_station_warnings(&subway).iter() {
+        println!("WARNING: {}", warning);
+    }
+
+    if let Some(ref arg) = export_file {
+        let path: Path = Path::new(arg);
+        let description: String = match path.extension_str() {
+            Some("toml") => export_toml(&subway),
+            _ => export_json(&subway),
+        };
+        if let Err(e) = io::File::create(&path).write_str(description.as_slice()) {
+            println!("ERROR: unable to write {}: {}", path.display(), e);
+            os::set_exit_status(1);
+        }
+        return;
+    }
+
+    if let Some(ref arg) = export_dat_dir {
+        let dir: Path = Path::new(arg);
+        for tline in subway.lines().iter() {
+            let path: Path = dir.join(format!("{}.dat", tline));
+            let written = export_subway_line(&subway, tline.as_slice()).map_err(|e| {
+                format!("{}", e)
+            }).and_then(|data| {
+                io::File::create(&path).write_str(data.as_slice()).map_err(|e| format!("{}", e))
+            });
+            if let Err(e) = written {
+                println!("ERROR: unable to write {}: {}", path.display(), e);
+                os::set_exit_status(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(ref scope) = dot_scope {
+        let dot = if scope.as_slice() == "all" {
+            export_dot(&subway, DotScope::All)
+        } else if scope.as_slice().starts_with("from ") {
+            route_for(&subway, scope.as_slice()).and_then(|route| {
+                export_dot(&subway, DotScope::Route(&route))
+            })
+        } else {
+            export_dot(&subway, DotScope::Line(scope.as_slice()))
+        };
+        match dot {
+            Ok(dot) => print!("{}", dot),
+            Err(e) => {
+                println!("ERROR: {}", e.describe(&subway));
+                os::set_exit_status(1);
+            },
+        }
+        return;
+    }
+
+    if let Some(ref scope) = geojson_scope {
+        let geojson = if scope.as_slice() == "all" {
+            Ok(export_geojson(&subway))
+        } else {
+            route_for(&subway, scope.as_slice()).map(|route| export_route_geojson(&subway, &route))
+        };
+        match geojson {
+            Ok(geojson) => println!("{}", geojson),
+            Err(e) => {
+                println!("ERROR: {}", e.describe(&subway));
+                os::set_exit_status(1);
+            },
+        }
+        return;
+    }
+
+    let log: Box<Writer + Send> = match audit_log {
+        Some(ref arg) => {
+            let path: Path = Path::new(arg);
+            match io::File::open_mode(&path, io::Append, io::Write) {
+                Ok(file) => Box::new(file) as Box<Writer + Send>,
+                Err(e) => {
+                    println!("ERROR: unable to open {}: {}", path.display(), e);
+                    os::set_exit_status(1);
+                    return;
+                },
+            }
+        },
+        None => Box::new(io::stdout()) as Box<Writer + Send>,
+    };
+    if admin_token.is_none() {
+        println!("WARNING: no admin token given, the subway cannot be changed");
+    }
+    let admin = Arc::new(Admin::new(admin_token, log));
+
+    if let Some(ref arg) = journal_file {
+        let path: Path = Path::new(arg);
+        match io::File::open_mode(&path, io::Append, io::Write) {
+            Ok(file) => subway.journal = Some(Journal::new(Box::new(file) as Box<Writer + Send>)),
+            Err(e) => {
+                println!("ERROR: unable to open {}: {}", path.display(), e);
+                os::set_exit_status(1);
+                return;
+            },
+        }
+    }
+
+    let shared_subway = Arc::new(RwLock::new(subway));
+    if let Some(addr) = http_addr {
+        let shared_subway = shared_subway.clone();
+        let admin = admin.clone();
+        Thread::spawn(move || t_query::http::start(addr.as_slice(), shared_subway, admin));
+    }
+    t_query::server::start(BIND_ADDR, shared_subway.clone(), admin);
 }
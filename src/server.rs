@@ -4,11 +4,10 @@
 
 use std::os;
 use std::io;
+use std::cmp;
 
 use std::io::fs::PathExtensions;
-use std::sync::{Arc, Mutex};
-
-use std::sync::mpsc::{sync_channel, channel, Sender};
+use std::sync::{Arc, RwLock};
 
 use std::io::{
     TcpListener,
@@ -24,83 +23,374 @@ use std::io::{
 use std::thread::Thread;
 use std::io::net::tcp::TcpAcceptor;
 
-use super::find_route;
+use super::{find_route, format_route};
+use super::subway::route::find_route_at;
+use super::subway::schedule::{describe_disruption, format_time};
+use super::TQueryError;
 use super::subway::Subway;
 use super::subway::route::Query;
-use super::subway::route::Query::{Route, Enable, Disable};
+use super::session::Session;
+use super::admin::{Admin, describe_command};
+use super::subway::route::Query::{Route, Enable, Disable, Alias};
 
 // chosen arbitrarily
 const MAX_QUERY_LENGTH: usize = 1024;
 
-pub fn start(bind_addr: &str, shared_subway: Arc<Mutex<Subway>>) {
-    type Message = (Result<Query, String>, Sender<String>);
-    // create rendezvous channel for queries and results
-    let (queue_back, queue_front) = sync_channel::<Message>(0);
-
-    // query handler
-    {
-        let shared_subway = shared_subway.clone();
-        Thread::spawn(move||{
-            loop {
-                let (maybe_query, results_chan) = queue_front.recv().unwrap();
-                match maybe_query {
-                    Ok(q) => match q {
-                        Query::Route(from, to) => {
-                            let subway = shared_subway.lock().unwrap();
-                            // double unwrap to silence "unused result" warning
-                            find_route(&*subway, from, to).map_err(|e| results_chan.send(e))
-                                                          .map(|p| results_chan.send(p))
-                                                          .unwrap().unwrap();
-                        },
-                        Query::Enable(stn) => {
-                            let mut subway = shared_subway.lock().unwrap();
-                            println!("enabling {}", stn);
-                            subway.enable_station(stn);
-                            results_chan.send("done".to_string()).unwrap();
-                        },
-                        Query::Disable(stn) => {
-                            println!("disabling {}", stn);
-                            let mut subway = shared_subway.lock().unwrap();
-                            subway.disable_station(stn);
-                            results_chan.send("done".to_string()).unwrap();
-                        }
-                    },
-                    Err(e) => { results_chan.send(e).unwrap(); },
-                }
+/// Ends every reply, so clients can tell where one stops on a connection kept open.
+pub const REPLY_END: &'static str = "\nEND\n";
+
+/// Reads the next query sent by the client, a line without its terminator, however
+/// many reads it arrives in. `None` once the client has disconnected. Lines longer
+/// than `MAX_QUERY_LENGTH` are skipped over and reported as errors.
+fn read_query<B: Buffer>(stream: &mut B) -> Option<Result<String, TQueryError>> {
+    let mut line: Vec<u8> = vec![];
+    let mut too_long: bool = false;
+    loop {
+        let (used, done) = match stream.fill_buf() {
+            Ok(available) => {
+                let end: Option<usize> = available.iter().position(|b| *b == b'\n');
+                let length: usize = end.unwrap_or(available.len());
+                let room: usize = MAX_QUERY_LENGTH - line.len();
+                if length > room { too_long = true; }
+                line.push_all(available.slice_to(cmp::min(length, room)));
+                (end.map(|e| e + 1).unwrap_or(length), end.is_some())
+            },
+            // the client disconnected, perhaps in the middle of a line
+            Err(_) if line.is_empty() => return None,
+            Err(_) => (0, true),
+        };
+        stream.consume(used);
+        if done { break; }
+    }
+
+    let query: String = String::from_utf8_lossy(line.as_slice()).trim().to_string();
+    if too_long {
+        return Some(Err(TQueryError::ParseQuery { query: format!("{}...", query) }));
+    }
+    Some(Ok(query))
+}
+
+/// Carries out `query` against the shared subway and returns the reply. Route queries
+/// only read the subway, so any number of them run at once; changes to it wait for
+/// them, and each other, to finish.
+pub fn answer(shared_subway: &RwLock<Subway>, query: Result<Query, TQueryError>) -> String {
+    match query {
+        Ok(Query::Route(from, to)) => {
+            let subway = shared_subway.read().unwrap();
+            match find_route(&*subway, from, to) {
+                Ok(route) => format_route(&*subway, &route),
+                Err(e) => e.describe(&*subway),
             }
-        });
+        },
+        Ok(Query::RouteAt(from, to, at)) => {
+            let subway = shared_subway.read().unwrap();
+            match find_route_at(&*subway, from, to, at) {
+                Ok(route) => format_route(&*subway, &route),
+                Err(e) => e.describe(&*subway),
+            }
+        },
+        Ok(Query::ListSchedule) => {
+            let subway = shared_subway.read().unwrap();
+            if subway.schedule.is_empty() { return "nothing scheduled".to_string(); }
+            let entries: Vec<String> = subway.schedule.iter().enumerate().map(|(i, d)| {
+                format!("{}. {}", i + 1, describe_disruption(&*subway, d))
+            }).collect();
+            entries.connect("\n")
+        },
+        Ok(Query::Status) => {
+            let subway = shared_subway.read().unwrap();
+            if subway.disabled_stations.is_empty() { return "no stations disabled".to_string(); }
+            let mut entries: Vec<String> = subway.disabled_stations.iter().map(|stn| {
+                let label: &str = subway.get_station(*stn).map(|s| s.as_slice()).unwrap_or("?");
+                let status: String = match subway.disabled_since.get(stn) {
+                    Some(since) => format!("{} disabled since {}", label, format_time(*since)),
+                    None => format!("{} disabled", label),
+                };
+                let incidents: Vec<String> = subway.incidents_holding(*stn);
+                if incidents.is_empty() { return status; }
+                format!("{} for incident {}", status, incidents.connect(", "))
+            }).collect();
+            entries.sort();
+            entries.connect("\n")
+        },
+        Ok(query) => change(shared_subway, |&: subway: &mut Subway| subway.apply(&query)),
+        Err(e) => e.describe(&*shared_subway.read().unwrap()),
+    }
+}
+
+/// Makes a change to the shared subway that may not apply to it, replying with why not
+/// if it does not.
+fn change<F>(shared_subway: &RwLock<Subway>, change: F) -> String
+             where F: Fn(&mut Subway) -> Result<(), TQueryError> {
+    let mut subway = shared_subway.write().unwrap();
+    match change(&mut *subway) {
+        Ok(()) => "done".to_string(),
+        Err(e) => e.describe(&*subway),
     }
+}
+
+/// `line` fit to print, i.e. with any admin token sent in it hidden.
+fn redact(line: &str) -> &str {
+    if line.starts_with("auth ") { "auth ***" } else { line }
+}
+
+/// Answers `line`, sent by `client` in `session`. Only sessions authenticated with
+/// `auth TOKEN` may change the subway, and every attempt to is audited.
+pub fn handle_line(shared_subway: &RwLock<Subway>, admin: &Admin, session: &mut Session,
+                   client: &str, line: &str) -> String {
+    if line.starts_with("auth ") {
+        if admin.check(line.slice_from("auth ".len()).trim()) {
+            session.authenticate();
+            admin.audit(client, "auth", true);
+            return "authenticated".to_string();
+        }
+        admin.audit(client, "auth", false);
+        return format!("{}", TQueryError::Unauthorized { command: "auth".to_string() });
+    }
+
+    let query = {
+        let subway = shared_subway.read().unwrap();
+        let query = session.query(&*subway, line).map(|query| match query {
+            // the disruption numbered now, as the schedule may change before it is called off
+            Query::Unschedule(n) => match n.checked_sub(1).and_then(|i| subway.schedule.get(i)) {
+                Some(disruption) => Query::UnscheduleDisruption(disruption.clone()),
+                None => Query::Unschedule(n),
+            },
+            query => query,
+        });
+        match query {
+            Ok(ref query) if query.is_mutation() => {
+                let command: String = describe_command(&*subway, query);
+                admin.audit(client, command.as_slice(), session.is_authenticated());
+                if session.is_authenticated() {
+                    Ok(query.clone())
+                } else {
+                    Err(TQueryError::Unauthorized { command: command })
+                }
+            },
+            query => query,
+        }
+    };
+    answer(shared_subway, query)
+}
 
+pub fn start(bind_addr: &str, shared_subway: Arc<RwLock<Subway>>, admin: Arc<Admin>) {
     let listener: TcpListener = TcpListener::bind(bind_addr).unwrap();
     let mut acceptor: TcpAcceptor = listener.listen().unwrap();
     for stream in acceptor.incoming() {
         match stream {
             Err(e) => { println!("error: {}", e) }
-            Ok(stream) => {
-                let queue_back = queue_back.clone();
+            Ok(mut stream) => {
+                let client: String = stream.peer_name().map(|addr| format!("{}", addr))
+                                                       .unwrap_or("unknown".to_string());
                 let mut streambuf: BufferedStream<TcpStream> = BufferedStream::new(stream);
                 let shared_subway = shared_subway.clone();
+                let admin = admin.clone();
                 Thread::spawn(move || {
-                    let mut buf: [u8; MAX_QUERY_LENGTH] = [0; MAX_QUERY_LENGTH];
-                    let bytes_read: usize = streambuf.read(&mut buf).unwrap();
+                    let mut session = Session::new();
+                    while let Some(line) = read_query(&mut streambuf) {
+                        let results: String = match line {
+                            Ok(ref query_str) if query_str.is_empty() => continue,
+                            Ok(query_str) => {
+                                println!("recvd: {}\n", redact(query_str.as_slice()));
+                                handle_line(&*shared_subway, &*admin, &mut session,
+                                            client.as_slice(), query_str.as_slice())
+                            },
+                            Err(e) => answer(&*shared_subway, Err(e)),
+                        };
+                        if streambuf.write_str(results.as_slice()).is_err() ||
+                           streambuf.write_str(REPLY_END).is_err() ||
+                           streambuf.flush().is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        }
+    }
+}
 
-                    println!("recvd: {}\n", ::std::str::from_utf8(&buf).unwrap());
+#[cfg(test)]
+mod server_tests {
+    use std::io::{BufferedReader, MemReader};
 
-                    let query_bytes: &[u8] = buf.slice_to(bytes_read);
-                    let query_str: &str = ::std::str::from_utf8(query_bytes).unwrap();
+    use std::sync::RwLock;
 
-                    let (done_send, done_recv) = channel::<String>();
+    use std::io::ChanWriter;
+    use std::sync::mpsc::channel;
 
-                    let subway = shared_subway.lock().unwrap();
-                    let query = Query::new(&*subway, query_str.trim());
-                    drop(subway);
+    use super::{read_query, answer, handle_line, redact, MAX_QUERY_LENGTH};
+    use admin::Admin;
+    use session::Session;
+    use subway::Subway;
+    use subway::route::Query;
+    use subway::schedule::{FixedClock, parse_time};
 
-                    queue_back.send((query, done_send)).unwrap();
+    /// A reader handing out `data` a few bytes at a time, as a slow client would.
+    fn trickle(data: &[u8]) -> BufferedReader<MemReader> {
+        BufferedReader::with_capacity(3, MemReader::new(data.to_vec()))
+    }
 
-                    let results: String = done_recv.recv().unwrap();
-                    streambuf.write_str(results.as_slice()).unwrap();
-                });
-            }
+    #[test]
+    fn test_read_query() {
+        let mut stream = trickle(b"from Davis to Park Street\r\n\ndisable Davis");
+        assert_eq!(read_query(&mut stream), Some(Ok("from Davis to Park Street".to_string())));
+        assert_eq!(read_query(&mut stream), Some(Ok("".to_string())));
+        assert_eq!(read_query(&mut stream), Some(Ok("disable Davis".to_string())));
+        assert_eq!(read_query(&mut stream), None);
+    }
+
+    #[test]
+    fn test_read_query_too_long() {
+        let mut data: Vec<u8> = range(0, MAX_QUERY_LENGTH + 10).map(|_| b'x').collect();
+        data.push_all(b"\nenable Davis\n\xff\n");
+        let mut stream = trickle(data.as_slice());
+        assert_eq!(read_query(&mut stream).unwrap().unwrap_err().code(), "parse_query");
+        assert_eq!(read_query(&mut stream), Some(Ok("enable Davis".to_string())));
+        assert_eq!(read_query(&mut stream), Some(Ok("\u{fffd}".to_string())));
+        assert_eq!(read_query(&mut stream), None);
+    }
+
+    #[test]
+    fn test_answer() {
+        let mut subway = Subway::new();
+        let a_id = subway.add_station("A");
+        let b_id = subway.add_station("B");
+        subway.add_connection(a_id, b_id, "x", "x");
+        subway.add_connection(b_id, a_id, "x", "x");
+        let shared_subway = RwLock::new(subway);
+
+        assert!(answer(&shared_subway, Ok(Query::Route(a_id, b_id))).as_slice().contains("x"));
+        assert_eq!(answer(&shared_subway, Ok(Query::Disable(b_id))).as_slice(), "done");
+        assert!(shared_subway.read().unwrap().disabled_stations.contains(&b_id));
+        assert_eq!(answer(&shared_subway, Ok(Query::Enable(b_id))).as_slice(), "done");
+        assert!(shared_subway.read().unwrap().disabled_stations.is_empty());
+    }
+
+    #[test]
+    fn test_answer_schedule() {
+        let mut subway = Subway::new();
+        let a_id = subway.add_station("A");
+        let b_id = subway.add_station("B");
+        subway.add_connection(a_id, b_id, "x", "x");
+        let shared_subway = RwLock::new(subway);
+        let query = |&: line: &str| -> String {
+            let parsed = Query::new(&*shared_subway.read().unwrap(), line);
+            answer(&shared_subway, parsed)
+        };
+
+        assert_eq!(query("schedule").as_slice(), "nothing scheduled");
+        assert_eq!(query("schedule station B from 2015-01-24 05:00 until 2015-01-26 03:00")
+                       .as_slice(), "done");
+        assert!(query("schedule line y from 2015-01-24 05:00 until 2015-01-26 03:00")
+                    .as_slice().starts_with("No such line"));
+        assert_eq!(query("schedule").as_slice(),
+                   "1. station B from 2015-01-24 05:00 until 2015-01-26 03:00");
+        assert_eq!(query("from A to B depart at 2015-01-25 10:00").as_slice(),
+                   "Station disabled: B");
+        assert!(query("from A to B depart at 2015-01-26 03:00").as_slice().contains("take x"));
+        assert!(query("unschedule 2").as_slice().starts_with("invalid choice"));
+        assert!(query("unschedule station A from 2015-01-24 05:00 until 2015-01-26 03:00")
+                    .as_slice().starts_with("Not scheduled"));
+        assert_eq!(query("unschedule 1").as_slice(), "done");
+        assert!(shared_subway.read().unwrap().schedule.is_empty());
+    }
+
+    #[test]
+    fn test_answer_status() {
+        let mut subway = Subway::new();
+        let a_id = subway.add_station("A");
+        let b_id = subway.add_station("B");
+        subway.add_connection(a_id, b_id, "x", "x");
+        subway.clock = Box::new(FixedClock(parse_time("2015-01-24 05:00").unwrap()));
+        let shared_subway = RwLock::new(subway);
+
+        assert_eq!(answer(&shared_subway, Ok(Query::Status)).as_slice(), "no stations disabled");
+        assert_eq!(answer(&shared_subway, Ok(Query::Disable(b_id))).as_slice(), "done");
+        assert_eq!(answer(&shared_subway, Ok(Query::Status)).as_slice(),
+                   "B disabled since 2015-01-24 05:00");
+        let incident = Query::DisableStations(vec![a_id, b_id], Some("42".to_string()));
+        assert_eq!(answer(&shared_subway, Ok(incident)).as_slice(), "done");
+        assert_eq!(answer(&shared_subway, Ok(Query::Status)).as_slice(),
+                   "A disabled since 2015-01-24 05:00 for incident 42\n\
+                    B disabled since 2015-01-24 05:00");
+        assert_eq!(answer(&shared_subway, Ok(Query::EnableIncident("42".to_string()))).as_slice(),
+                   "done");
+        assert!(answer(&shared_subway, Ok(Query::EnableIncident("42".to_string()))).as_slice()
+                    .starts_with("No such incident"));
+        assert_eq!(answer(&shared_subway, Ok(Query::Status)).as_slice(),
+                   "B disabled since 2015-01-24 05:00");
+    }
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(redact("auth s3cret"), "auth ***");
+        assert_eq!(redact("disable A"), "disable A");
+    }
+
+    #[test]
+    fn test_handle_line_needs_admin() {
+        let mut subway = Subway::new();
+        let a_id = subway.add_station("A");
+        let b_id = subway.add_station("B");
+        subway.add_connection(a_id, b_id, "x", "x");
+        let shared_subway = RwLock::new(subway);
+        let (send, recv) = channel();
+        let admin = Admin::new(Some("s3cret".to_string()), Box::new(ChanWriter::new(send)));
+        let mut session = Session::new();
+        let handle = |&: session: &mut Session, line: &str| -> String {
+            handle_line(&shared_subway, &admin, session, "client", line)
+        };
+
+        assert!(handle(&mut session, "from A to B").as_slice().contains("take x"));
+        assert!(handle(&mut session, "disable B").as_slice().starts_with("unauthorized"));
+        assert!(handle(&mut session, "auth guess").as_slice().starts_with("unauthorized"));
+        assert!(shared_subway.read().unwrap().disabled_stations.is_empty());
+
+        assert_eq!(handle(&mut session, "auth s3cret").as_slice(), "authenticated");
+        assert_eq!(handle(&mut session, "disable B").as_slice(), "done");
+        assert!(shared_subway.read().unwrap().disabled_stations.contains(&b_id));
+
+        let audited: Vec<String> = range(0, 4).map(|_| {
+            let entry = String::from_utf8(recv.recv().unwrap()).unwrap();
+            entry.as_slice().splitn(1, ' ').nth(1).unwrap().to_string()
+        }).collect();
+        assert_eq!(audited, vec!["client denied disable B (1)\n".to_string(),
+                                 "client denied auth\n".to_string(),
+                                 "client allowed auth\n".to_string(),
+                                 "client allowed disable B (1)\n".to_string()]);
+    }
+
+    #[test]
+    fn test_handle_line_unschedule() {
+        let mut subway = Subway::new();
+        let a_id = subway.add_station("A");
+        let b_id = subway.add_station("B");
+        subway.add_connection(a_id, b_id, "x", "x");
+        let shared_subway = RwLock::new(subway);
+        let (send, recv) = channel();
+        let admin = Admin::new(Some("s3cret".to_string()), Box::new(ChanWriter::new(send)));
+        let mut session = Session::new();
+        let mut handle = |&mut: line: &str| -> String {
+            handle_line(&shared_subway, &admin, &mut session, "client", line)
+        };
+
+        assert_eq!(handle("auth s3cret").as_slice(), "authenticated");
+        for target in ["station A", "station B"].iter() {
+            let line = format!("schedule {} from 2015-01-24 05:00 until 2015-01-26 03:00", target);
+            assert_eq!(handle(line.as_slice()).as_slice(), "done");
         }
+        assert_eq!(handle("unschedule 2").as_slice(), "done");
+        assert!(handle("unschedule 2").as_slice().starts_with("invalid choice"));
+
+        let audited: Vec<String> = range(0, 5).map(|_| {
+            let entry = String::from_utf8(recv.recv().unwrap()).unwrap();
+            entry.as_slice().splitn(1, ' ').nth(1).unwrap().to_string()
+        }).collect();
+        // audited as the disruption called off, not by its place in the schedule
+        assert_eq!(audited[3].as_slice(),
+                   "client allowed unschedule station B from 2015-01-24 05:00 \
+                    until 2015-01-26 03:00\n");
+        assert_eq!(shared_subway.read().unwrap().schedule.len(), 1);
     }
 }
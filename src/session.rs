@@ -0,0 +1,146 @@
+//! State kept for each client connection between queries. When a station name in a
+//! query matches several stations, the session remembers the query, and the client
+//! finishes it by replying `choose N` with the number of one of the candidates offered.
+#![allow(unstable)]
+
+use std::collections::HashMap;
+
+use error::TQueryError;
+use subway::{Subway, StationId};
+use subway::route::Query;
+
+/// A query held back until the client picks one of `candidates` for `name`.
+struct Pending {
+    line: String,
+    name: String,
+    candidates: Vec<StationId>,
+}
+
+pub struct Session {
+    pending: Option<Pending>,
+    /// station name -> station chosen for it, for the query awaiting choices
+    choices: HashMap<String, StationId>,
+    /// Whether the client has authenticated as an admin, see `admin`
+    authenticated: bool,
+}
+
+impl Session {
+    /// Creates a new `Session`
+    pub fn new() -> Session {
+        Session { pending: None, choices: HashMap::new(), authenticated: false }
+    }
+
+    /// Lets the client change the subway from now on.
+    pub fn authenticate(&mut self) {
+        self.authenticated = true;
+    }
+
+    /// Whether the client may change the subway.
+    pub fn is_authenticated(&self) -> bool {
+        self.authenticated
+    }
+
+    /// Whether the session is waiting for the client to choose a station.
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Parses `line`, sent by the client, into a `Query`. `choose N` picks a station
+    /// for the query held back by the last ambiguous station name and parses it again.
+    pub fn query(&mut self, subway: &Subway, line: &str) -> Result<Query, TQueryError> {
+        let line: &str = line.trim();
+        if line.starts_with("choose ") || line == "choose" {
+            let choice: &str = line.slice_from("choose".len()).trim();
+            let (line, name, stn_id) = match self.pending {
+                None => return Err(TQueryError::InvalidChoice { choice: choice.to_string(),
+                                                                count: 0 }),
+                Some(ref pending) => {
+                    let count: usize = pending.candidates.len();
+                    match choice.parse::<usize>() {
+                        Some(n) if n >= 1 && n <= count => {
+                            (pending.line.clone(), pending.name.clone(), pending.candidates[n - 1])
+                        },
+                        _ => return Err(TQueryError::InvalidChoice { choice: choice.to_string(),
+                                                                     count: count }),
+                    }
+                },
+            };
+            self.choices.insert(name, stn_id);
+            return self.resolve(subway, line.as_slice());
+        }
+        self.pending = None;
+        self.choices.clear();
+        self.resolve(subway, line)
+    }
+
+    /// Parses `line` using the stations chosen so far, holding it back if a station
+    /// name in it is ambiguous.
+    fn resolve(&mut self, subway: &Subway, line: &str) -> Result<Query, TQueryError> {
+        let parsed = {
+            let choices = &self.choices;
+            Query::parse(line, |&: stn: &str| match choices.get(stn) {
+                Some(id) => Ok(*id),
+                None => subway.find_station(stn),
+            })
+        };
+        self.pending = match parsed {
+            Err(TQueryError::AmbiguousStation { ref query, ref candidates }) => {
+                Some(Pending { line: line.to_string(), name: query.clone(),
+                               candidates: candidates.clone() })
+            },
+            _ => None,
+        };
+        if self.pending.is_none() { self.choices.clear(); }
+        parsed
+    }
+}
+
+#[cfg(test)]
+mod session_tests {
+    use super::Session;
+    use subway::Subway;
+    use subway::route::Query;
+
+    fn sample() -> Subway {
+        let mut subway = Subway::new();
+        subway.add_station("Park Street");
+        subway.add_station("Park Street Station (C)");
+        subway.add_station("Harvard Square");
+        subway.add_station("Harvard Avenue");
+        subway.add_station("Davis");
+        subway
+    }
+
+    #[test]
+    fn test_choose() {
+        let subway = sample();
+        let mut session = Session::new();
+        assert_eq!(session.query(&subway, "choose 1").unwrap_err().code(), "invalid_choice");
+
+        let ambiguous = session.query(&subway, "from park to harvard");
+        assert_eq!(ambiguous.unwrap_err().code(), "ambiguous_station");
+        assert!(session.is_pending());
+        assert_eq!(session.query(&subway, "choose 3").unwrap_err().code(), "invalid_choice");
+        assert_eq!(session.query(&subway, "choose x").unwrap_err().code(), "invalid_choice");
+        assert!(session.is_pending());
+
+        // the destination is ambiguous too
+        assert_eq!(session.query(&subway, "choose 2").unwrap_err().code(), "ambiguous_station");
+        match session.query(&subway, "choose 1") {
+            Ok(Query::Route(from, to)) => assert_eq!((from, to), (1, 2)),
+            _ => panic!("expected a route"),
+        }
+        assert!(!session.is_pending());
+        assert_eq!(session.query(&subway, "choose 1").unwrap_err().code(), "invalid_choice");
+    }
+
+    #[test]
+    fn test_new_query_abandons_choice() {
+        let subway = sample();
+        let mut session = Session::new();
+        assert!(session.query(&subway, "disable park").is_err());
+        assert!(session.is_pending());
+        assert!(session.query(&subway, "disable davis").unwrap().is_disable());
+        assert!(!session.is_pending());
+    }
+}
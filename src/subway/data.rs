@@ -1,11 +1,293 @@
+//! Loads subway lines from `.dat` files. A file describes a single line:
+//!
+//! ```text
+//! --- B C            header, the branches of the line (or just the line's name)
+//! Trunk Station      a station on the trunk
+//! ------ B           branch B starts at the last trunk station
+//!     Branch Station a station on branch B, indented
+//! --- C              back to the last trunk station, the trunk continues as C
+//! Name [key]         a station sharing its name with another, told apart by `key`
+//! Next Station | 3   a station 3 minutes from the one before it
+//! ```
+//!
+//! Segments without a time in minutes are routed as if `DEFAULT_SEGMENT_MINUTES` long,
+//! but routes along them have no estimated travel time.
+//!
+//! Interchanges between lines are declared in a separate transfers file, one per line:
+//!
+//! ```text
+//! # comment
+//! Park Street Station | 3 | 2      station, transfer cost, minutes walking
+//! Haymarket Station = Haymarket    one interchange spelled differently on two lines
+//! ```
+//!
+//! Both the cost and the walking time are optional.
+//!
+//! Other names riders know stations by are given in an aliases file:
+//!
+//! ```text
+//! DTX, Downtown = Downtown Crossing Station    aliases, then the station they stand for
+//! ```
+//!
+//! Station locations are given in a coordinates file, also one per line:
+//!
+//! ```text
+//! Park Street Station | 42.35639 | -71.0624     station, latitude, longitude
+//! ```
+//!
+//! Disruptions planned ahead are given in a schedule file, one per line, closing a
+//! `station`, `segment`, `branch` or `line` from one time until another, in UTC:
+//!
+//! ```text
+//! segment Kenmore Station to Blandford Street | 2015-01-24 05:00 | 2015-01-26 03:00
+//! branch Mattapan | 2015-01-31 05:00 | 2015-02-02 03:00
+//! ```
 #![allow(unstable)]
 use std::io;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::collections::hash_map::Entry::{Occupied, Vacant};
 
-use super::{Subway, StationId};
+use error::TQueryError;
 
+use super::{Subway, StationId, Coord};
+use super::names::normalize;
+use super::schedule::{Disruption, parse_closure, parse_time};
+
+/// A single line of a subway data file, classified.
+enum DataLine<'a> {
+    /// `--- X`, the trunk continues from the last branch point as `X`
+    Converge(&'a str),
+    /// `---- X`, a branch named `X` starts at the last trunk station
+    Branch(&'a str),
+    /// A station name, and whether it was indented
+    Station(&'a str, bool),
+    Blank,
+}
+
+/// Splits a station line into the station entry and the minutes from the previous
+/// station, if given as a trailing `| N`.
+fn split_minutes(entry: &str) -> (&str, Option<&str>) {
+    match entry.find('|') {
+        Some(bar) => (entry.slice_to(bar).trim(), Some(entry.slice_from(bar + 1).trim())),
+        None => (entry, None),
+    }
+}
+
+/// Splits a station entry into its name and, for stations marked distinct with a
+/// trailing `[key]`, the key telling it apart from others of the same name.
+fn parse_station(entry: &str) -> (&str, Option<&str>) {
+    if entry.ends_with("]") {
+        if let Some(open) = entry.rfind('[') {
+            let name = entry.slice_to(open).trim();
+            let key = entry.slice(open + 1, entry.len() - 1).trim();
+            return (name, Some(key));
+        }
+    }
+    (entry, None)
+}
+
+fn classify(line: &str) -> DataLine {
+    let to_trim: &[_] = &['-', ' '];
+    if line.trim().is_empty() {
+        DataLine::Blank
+    } else if line.starts_with("--- ") {
+        DataLine::Converge(line.trim_left_matches(to_trim).trim())
+    } else if line.starts_with("----") {
+        DataLine::Branch(line.trim_left_matches(to_trim).trim())
+    } else {
+        let indented: bool = line.chars().next().map(|c| c.is_whitespace()).unwrap_or(false);
+        DataLine::Station(line.trim(), indented)
+    }
+}
+
+fn data_error(file: &str, line: usize, reason: String) -> TQueryError {
+    TQueryError::DataFormat { file: file.to_string(), line: line, reason: reason }
+}
+
+/// Reads every line of `content`, without line terminators.
+fn read_lines<R: Reader>(mut content: io::BufferedReader<R>, file: &str)
+                         -> Result<Vec<String>, TQueryError> {
+    let mut lines: Vec<String> = vec![];
+    for (i, l) in content.lines().enumerate() {
+        match l {
+            Ok(line) => lines.push(line.trim_right().to_string()),
+            Err(e) => return Err(data_error(file, i + 1, format!("unable to read line: {}", e))),
+        }
+    }
+    Ok(lines)
+}
+
+/// Checks the data for line `tline` and returns every problem found, each naming
+/// `file` and the line it was found on. An empty list means the data can be loaded.
+pub fn validate_subway_data<R: Reader>(content: io::BufferedReader<R>,
+                                       tline: &str, file: &str) -> Vec<TQueryError> {
+    match read_lines(content, file) {
+        Ok(lines) => validate_lines(lines.as_slice(), tline, file),
+        Err(e) => vec![e],
+    }
+}
+
+fn validate_lines(lines: &[String], tline: &str, file: &str) -> Vec<TQueryError> {
+    let mut problems: Vec<TQueryError> = vec![];
+
+    let header: &str = match lines.first() {
+        Some(l) if l.starts_with("---") => l.as_slice(),
+        Some(_) => {
+            problems.push(data_error(file, 1, "expected a `--- BRANCH ...` header".to_string()));
+            return problems;
+        },
+        None => {
+            problems.push(data_error(file, 1, "empty file, expected a header".to_string()));
+            return problems;
+        },
+    };
+    let to_trim: &[_] = &['-', ' '];
+    let declared: Vec<&str> = header.trim_left_matches(to_trim).split(' ')
+                                    .filter(|b| !b.is_empty())
+                                    .collect();
+    if declared.is_empty() {
+        problems.push(data_error(file, 1, "header declares no branches".to_string()));
+    }
+
+    // line number and name of the branch section being read, and whether it has stations
+    let mut section: Option<(usize, &str)> = None;
+    let mut section_has_stations: bool = false;
+    let mut seen_branches: Vec<&str> = vec![];
+    let mut segment: &str = tline;
+    let mut segment_stations: HashMap<&str, HashSet<&str>> = HashMap::new();
+    let mut seen_station: bool = false;
+
+    for (i, line) in lines.iter().enumerate().skip(1) {
+        let line_no: usize = i + 1;
+        match classify(line.as_slice()) {
+            DataLine::Blank => {},
+            DataLine::Converge(label) => {
+                if let Some((start, name)) = section {
+                    if !section_has_stations {
+                        problems.push(data_error(file, start,
+                                                 format!("branch `{}` has no stations", name)));
+                    }
+                }
+                for b in label.split(' ').filter(|b| !b.is_empty()) {
+                    if !declared.contains(&b) && b != tline {
+                        problems.push(data_error(file, line_no,
+                                                 format!("converge line names unknown branch `{}`",
+                                                         b)));
+                    }
+                }
+                section = None;
+                segment = label;
+            },
+            DataLine::Branch(name) => {
+                if let Some((start, prev)) = section {
+                    if !section_has_stations {
+                        problems.push(data_error(file, start,
+                                                 format!("branch `{}` has no stations", prev)));
+                    }
+                }
+                if !declared.contains(&name) {
+                    problems.push(data_error(file, line_no,
+                                             format!("branch `{}` is not declared in header",
+                                                     name)));
+                } else if seen_branches.contains(&name) {
+                    problems.push(data_error(file, line_no,
+                                             format!("branch `{}` appears more than once", name)));
+                }
+                seen_branches.push(name);
+                section = Some((line_no, name));
+                section_has_stations = false;
+                segment = name;
+            },
+            DataLine::Station(entry, indented) => {
+                let (name, minutes) = split_minutes(entry);
+                match minutes {
+                    Some(m) if m.parse::<usize>().is_none() => {
+                        problems.push(data_error(file, line_no,
+                                                 format!("`{}` is not a number of minutes", m)));
+                    },
+                    Some(_) if !seen_station => {
+                        problems.push(data_error(file, line_no,
+                                                 format!("`{}` is timed but has no station \
+                                                          before it", name)));
+                    },
+                    _ => {},
+                }
+                seen_station = true;
+                match section {
+                    Some((_, branch)) if !indented => {
+                        problems.push(data_error(file, line_no,
+                                                 format!("station `{}` in branch `{}` is not indented",
+                                                         name, branch)));
+                    },
+                    None if indented => {
+                        problems.push(data_error(file, line_no,
+                                                 format!("stray indentation before `{}`, which is \
+                                                          not in a branch", name)));
+                    },
+                    _ => {},
+                }
+                match parse_station(name) {
+                    (_, Some("")) => {
+                        problems.push(data_error(file, line_no,
+                                                 format!("station `{}` has an empty key", name)));
+                    },
+                    ("", _) => {
+                        problems.push(data_error(file, line_no,
+                                                 format!("station `{}` has no name", name)));
+                    },
+                    _ => {},
+                }
+                section_has_stations = true;
+                let stations = match segment_stations.entry(segment) {
+                    Occupied(ent) => ent.into_mut(),
+                    Vacant(ent) => ent.insert(HashSet::new()),
+                };
+                if !stations.insert(name) {
+                    problems.push(data_error(file, line_no,
+                                             format!("station `{}` appears more than once in `{}`",
+                                                     name, segment)));
+                }
+            },
+        }
+    }
+    if let Some((start, name)) = section {
+        if !section_has_stations {
+            problems.push(data_error(file, start, format!("branch `{}` has no stations", name)));
+        }
+    }
+
+    if declared.len() > 1 || !seen_branches.is_empty() {
+        for b in declared.iter() {
+            if !seen_branches.contains(b) {
+                problems.push(data_error(file, 1,
+                                         format!("branch `{}` is declared in header but never \
+                                                  appears", b)));
+            }
+        }
+    }
+    problems
+}
+
+/// Loads the data for subway line `tline` into `subway`, failing on the first problem
+/// found in the data.
 pub fn load_subway_data<R: Reader>(mut subway: &mut Subway,
                                    mut content: io::BufferedReader<R>,
-                                   tline: &str) -> Result<(), String> {
+                                   tline: &str) -> Result<(), TQueryError> {
+    load_subway_data_checked(subway, content, tline, tline)
+        .map_err(|problems| problems.into_iter().next().unwrap())
+}
+
+/// Loads the data for subway line `tline` into `subway`, collecting every problem found
+/// in the data instead of stopping at the first. Problems name `file` and the line they
+/// were found on. Nothing is added to `subway` unless the data is free of problems.
+pub fn load_subway_data_checked<R: Reader>(mut subway: &mut Subway,
+                                           mut content: io::BufferedReader<R>,
+                                           tline: &str,
+                                           file: &str) -> Result<(), Vec<TQueryError>> {
+    let lines: Vec<String> = try!(read_lines(content, file).map_err(|e| vec![e]));
+    let problems = validate_lines(lines.as_slice(), tline, file);
+    if !problems.is_empty() { return Err(problems); }
 
     let mut subway_branch: String = tline.to_string();
     let mut prev_stn_id: Option<usize> = None;
@@ -13,65 +295,725 @@ pub fn load_subway_data<R: Reader>(mut subway: &mut Subway,
     let mut in_branch: bool = false;
     let mut just_branched: bool = false;
 
-    let to_trim: &[_] = &['-', ' '];
-
-    let mut lines = content.lines();
-    // TODO: use for graph validation, i.e. all branches listed appear
-    let branch_list_line = lines.next().unwrap().unwrap()
-                                                .trim_left_matches(to_trim)
-                                                .trim()
-                                                .to_string();
-    let num_branches: usize = branch_list_line.split(' ').count();
-    let mut seen_branches: usize = 0;
-    for l in lines {
-        let line = l.unwrap();
-        
-        let branch_converge_line: bool = line.starts_with("--- ");
-        if branch_converge_line {
-            subway_branch = line.trim_left_matches(to_trim).trim().to_string();
-            in_branch = false;
-            just_branched = false;
-            prev_stn_id = pre_branch_stn;
-            pre_branch_stn = None;
-            continue;
-        }
-        
-        let single_branch_line: bool = line.starts_with("----");
-        if single_branch_line {
-            in_branch = true;
-            just_branched = true;
-            seen_branches += 1;
-            subway_branch = line.trim_left_matches(to_trim).trim().to_string();
-            continue;
-        }
+    for line in lines.iter().skip(1) {
+        let station: &str = match classify(line.as_slice()) {
+            DataLine::Blank => continue,
+            DataLine::Converge(label) => {
+                subway_branch = label.to_string();
+                in_branch = false;
+                just_branched = false;
+                prev_stn_id = pre_branch_stn;
+                pre_branch_stn = None;
+                continue;
+            },
+            DataLine::Branch(name) => {
+                in_branch = true;
+                just_branched = true;
+                subway_branch = name.to_string();
+                continue;
+            },
+            DataLine::Station(entry, _) => entry,
+        };
 
-        let mut station: String  = line.trim().to_string();
-        // FIXME: this is a hack, need general solution to stations w/ same name but diff stn.
-        if station.as_slice() == "St. Paul Street" {
-            station.push_str(" "); station.push_str(subway_branch.as_slice());
-        }
-        let stn_id: StationId = subway.add_station(station.as_slice());
+        let (station, minutes) = split_minutes(station);
+        let minutes: Option<usize> = minutes.and_then(|m| m.parse::<usize>());
+        let stn_id: StationId = match parse_station(station) {
+            (name, Some(key)) => subway.add_distinct_station(name, key),
+            (name, None) => subway.add_station(name),
+        };
 
         if !in_branch { pre_branch_stn = Some(stn_id); }
 
         if in_branch && just_branched {
             if let Some(id) = pre_branch_stn {
-                subway.add_connection(id, stn_id, tline, subway_branch.as_slice());
-                subway.add_connection(stn_id, id, tline, subway_branch.as_slice());
+                subway.add_timed_connection(id, stn_id, tline, subway_branch.as_slice(), minutes);
+                subway.add_timed_connection(stn_id, id, tline, subway_branch.as_slice(), minutes);
             }
             just_branched = false;
             prev_stn_id = Some(stn_id);
             continue;
         }
         if let Some(sid) = prev_stn_id {
-            subway.add_connection(sid, stn_id, tline, subway_branch.as_slice());
-            subway.add_connection(stn_id, sid, tline, subway_branch.as_slice());
+            subway.add_timed_connection(sid, stn_id, tline, subway_branch.as_slice(), minutes);
+            subway.add_timed_connection(stn_id, sid, tline, subway_branch.as_slice(), minutes);
         }
         prev_stn_id = Some(stn_id);
     }
-    if (num_branches != 1) && (seen_branches != num_branches) {
-        Err(format!("More branches defined in header than appear in graph data for {}!", tline))
+    Ok(())
+}
+
+/// Loads transfer declarations from `content` into `subway`, whose lines must already
+/// be loaded. Every problem found is reported, naming `file` and the line it was found
+/// on, and no declaration is applied unless there are none.
+pub fn load_transfer_data<R: Reader>(subway: &mut Subway,
+                                     content: io::BufferedReader<R>,
+                                     file: &str) -> Result<(), Vec<TQueryError>> {
+    let lines: Vec<String> = try!(read_lines(content, file).map_err(|e| vec![e]));
+    let mut problems: Vec<TQueryError> = vec![];
+    let mut declarations: Vec<(Vec<StationId>, Option<usize>, usize)> = vec![];
+
+    for (i, line) in lines.iter().enumerate() {
+        let line_no: usize = i + 1;
+        let entry: &str = line.trim();
+        if entry.is_empty() || entry.starts_with("#") { continue; }
+
+        let fields: Vec<&str> = entry.split('|').map(|f| f.trim()).collect();
+        if fields.len() > 3 {
+            problems.push(data_error(file, line_no,
+                                     "expected `STATION [= STATION] | COST | WALK`".to_string()));
+            continue;
+        }
+
+        let mut numbers: Vec<Option<usize>> = vec![];
+        for field in fields.iter().skip(1) {
+            if field.is_empty() {
+                numbers.push(None);
+            } else if let Some(n) = field.parse::<usize>() {
+                numbers.push(Some(n));
+            } else {
+                problems.push(data_error(file, line_no, format!("`{}` is not a number", field)));
+            }
+        }
+        let cost: Option<usize> = numbers.get(0).and_then(|n| *n);
+        let walk: usize = numbers.get(1).and_then(|n| *n).unwrap_or(0);
+
+        let mut ids: Vec<StationId> = vec![];
+        for name in fields[0].split('=').map(|n| n.trim()) {
+            match subway.get_station_id(name) {
+                Some(id) => ids.push(*id),
+                None => problems.push(data_error(file, line_no,
+                                                 format!("unknown station `{}`", name))),
+            }
+        }
+        declarations.push((ids, cost, walk));
+    }
+    if !problems.is_empty() { return Err(problems); }
+
+    for &(ref ids, cost, walk) in declarations.iter() {
+        let keep: StationId = ids[0];
+        for other in ids.iter() {
+            subway.add_transfer(keep, *other, cost, walk);
+        }
+    }
+    Ok(())
+}
+
+/// Loads station aliases from `content` into `subway`, whose lines must already be
+/// loaded. Every problem found is reported, naming `file` and the line it was found on,
+/// and no alias is added unless there are none.
+pub fn load_alias_data<R: Reader>(subway: &mut Subway,
+                                  content: io::BufferedReader<R>,
+                                  file: &str) -> Result<(), Vec<TQueryError>> {
+    let lines: Vec<String> = try!(read_lines(content, file).map_err(|e| vec![e]));
+    let mut problems: Vec<TQueryError> = vec![];
+    // normalized alias -> (alias as written, station)
+    let mut aliases: BTreeMap<String, (String, StationId)> = BTreeMap::new();
+
+    for (i, line) in lines.iter().enumerate() {
+        let line_no: usize = i + 1;
+        let entry: &str = line.trim();
+        if entry.is_empty() || entry.starts_with("#") { continue; }
+
+        let (names, station) = match entry.rfind('=') {
+            Some(eq) => (entry.slice_to(eq), entry.slice_from(eq + 1).trim()),
+            None => {
+                problems.push(data_error(file, line_no,
+                                         "expected `ALIAS[, ALIAS ...] = STATION`".to_string()));
+                continue;
+            },
+        };
+        let stn_id: StationId = match subway.get_station_id(station) {
+            Some(id) => *id,
+            None => {
+                problems.push(data_error(file, line_no, format!("unknown station `{}`", station)));
+                continue;
+            },
+        };
+        for alias in names.split(',').map(|a| a.trim()) {
+            let key: String = normalize(alias);
+            if key.is_empty() {
+                problems.push(data_error(file, line_no, format!("`{}` is not an alias", alias)));
+                continue;
+            }
+            let earlier: Option<StationId> = aliases.get(&key).map(|&(_, id)| id)
+                                                    .or(subway.get_alias(alias).map(|id| *id));
+            if earlier.is_some() && earlier != Some(stn_id) {
+                problems.push(data_error(file, line_no,
+                                         format!("`{}` is already an alias of `{}`", alias,
+                                                 subway.stations[earlier.unwrap()])));
+                continue;
+            }
+            aliases.insert(key, (alias.to_string(), stn_id));
+        }
+    }
+    if !problems.is_empty() { return Err(problems); }
+
+    for (_, &(ref alias, stn_id)) in aliases.iter() {
+        if let Err(e) = subway.add_alias(alias.as_slice(), stn_id) { problems.push(e); }
+    }
+    if !problems.is_empty() { return Err(problems); }
+    Ok(())
+}
+
+/// Loads station locations from `content` into `subway`, whose lines must already be
+/// loaded. Every problem found is reported, naming `file` and the line it was found on,
+/// and no location is recorded unless there are none.
+pub fn load_coordinate_data<R: Reader>(subway: &mut Subway,
+                                       content: io::BufferedReader<R>,
+                                       file: &str) -> Result<(), Vec<TQueryError>> {
+    let lines: Vec<String> = try!(read_lines(content, file).map_err(|e| vec![e]));
+    let mut problems: Vec<TQueryError> = vec![];
+    let mut locations: Vec<(StationId, Coord)> = vec![];
+
+    for (i, line) in lines.iter().enumerate() {
+        let line_no: usize = i + 1;
+        let entry: &str = line.trim();
+        if entry.is_empty() || entry.starts_with("#") { continue; }
+
+        let fields: Vec<&str> = entry.split('|').map(|f| f.trim()).collect();
+        if fields.len() != 3 {
+            problems.push(data_error(file, line_no,
+                                     "expected `STATION | LATITUDE | LONGITUDE`".to_string()));
+            continue;
+        }
+        let id: Option<StationId> = subway.get_station_id(fields[0]).map(|id| *id);
+        if id.is_none() {
+            problems.push(data_error(file, line_no, format!("unknown station `{}`", fields[0])));
+        }
+        let coord = match (fields[1].parse::<f64>(), fields[2].parse::<f64>()) {
+            (Some(lat), Some(lon)) => Coord::new(lat, lon),
+            _ => None,
+        };
+        if coord.is_none() {
+            problems.push(data_error(file, line_no,
+                                     format!("`{} | {}` is not a valid latitude and longitude",
+                                             fields[1], fields[2])));
+        }
+        if let (Some(id), Some(coord)) = (id, coord) { locations.push((id, coord)); }
+    }
+    if !problems.is_empty() { return Err(problems); }
+
+    for &(id, coord) in locations.iter() {
+        subway.set_location(id, coord);
+    }
+    Ok(())
+}
+
+/// Loads planned disruptions from `content` into `subway`, whose lines must already be
+/// loaded. Every problem found is reported, naming `file` and the line it was found on,
+/// and nothing is scheduled unless there are none.
+pub fn load_schedule_data<R: Reader>(subway: &mut Subway,
+                                     content: io::BufferedReader<R>,
+                                     file: &str) -> Result<(), Vec<TQueryError>> {
+    let lines: Vec<String> = try!(read_lines(content, file).map_err(|e| vec![e]));
+    let mut problems: Vec<TQueryError> = vec![];
+    let mut disruptions: Vec<Disruption> = vec![];
+
+    for (i, line) in lines.iter().enumerate() {
+        let line_no: usize = i + 1;
+        let entry: &str = line.trim();
+        if entry.is_empty() || entry.starts_with("#") { continue; }
+
+        let fields: Vec<&str> = entry.split('|').map(|f| f.trim()).collect();
+        if fields.len() != 3 {
+            problems.push(data_error(file, line_no,
+                                     "expected `CLOSURE | FROM | UNTIL`".to_string()));
+            continue;
+        }
+        let find = |&: name: &str| -> Result<StationId, TQueryError> {
+            match subway.get_station_id(name) {
+                Some(id) => Ok(*id),
+                None => Err(TQueryError::NoSuchStation { query: name.to_string(),
+                                                         suggestions: vec![] }),
+            }
+        };
+        let disruption = parse_closure(fields[0], &find).and_then(|closure| {
+            let start = try!(parse_time(fields[1]));
+            let end = try!(parse_time(fields[2]));
+            Ok(Disruption { closure: closure, start: start, end: end })
+        });
+        match disruption.and_then(|d| subway.check_disruption(&d).map(|_| d)) {
+            Ok(d) => disruptions.push(d),
+            Err(e) => problems.push(data_error(file, line_no, e.describe(subway))),
+        }
+    }
+    if !problems.is_empty() { return Err(problems); }
+
+    for disruption in disruptions.into_iter() {
+        subway.schedule.push(disruption);
+    }
+    Ok(())
+}
+
+/// Returns a warning for each group of stations whose names differ only in case,
+/// punctuation or a "Station" suffix, and which are therefore probably one interchange
+/// missing a transfer declaration.
+pub fn unlinked_station_warnings(subway: &Subway) -> Vec<String> {
+    let mut groups: BTreeMap<String, Vec<StationId>> = BTreeMap::new();
+    for (id, label) in subway.stations.iter().enumerate() {
+        // merged stations are linked, distinct stations share a name on purpose
+        if !subway.is_station(id) { continue; }
+        if *label != subway.names[id] { continue; }
+
+        let key: String = normalize(label.as_slice());
+        if let Some(ids) = groups.get_mut(&key) {
+            ids.push(id);
+            continue;
+        }
+        groups.insert(key, vec![id]);
+    }
+
+    let mut warnings: Vec<String> = vec![];
+    for ids in groups.values().filter(|ids| ids.len() > 1) {
+        let names: Vec<String> = ids.iter().map(|id| format!("`{}`", subway.stations[*id]))
+                                    .collect();
+        warnings.push(format!("stations {} look alike but are not linked by a transfer",
+                              names.connect(", ")));
+    }
+    warnings
+}
+
+/// The entry for station `stn_id` in a `.dat` file, with its `[key]` if it is distinct.
+fn station_entry(subway: &Subway, stn_id: StationId) -> String {
+    let label: &str = subway.stations[stn_id].as_slice();
+    let name: &str = subway.names[stn_id].as_slice();
+    if label == name {
+        label.to_string()
+    } else {
+        format!("{} [{}]", name, label.slice(name.len() + 2, label.len() - 1))
+    }
+}
+
+fn minutes_suffix(minutes: Option<usize>) -> String {
+    minutes.map(|m| format!(" | {}", m)).unwrap_or(String::new())
+}
+
+/// The stations along the path through `adjacent` starting at `start`, in order.
+fn path_from(adjacent: &BTreeMap<StationId, Vec<StationId>>, start: StationId) -> Vec<StationId> {
+    let mut path: Vec<StationId> = vec![start];
+    loop {
+        let next: Option<StationId> = {
+            let cur: StationId = *path.last().unwrap();
+            adjacent.get(&cur).and_then(|ns| ns.iter().find(|n| !path.contains(*n))).map(|n| *n)
+        };
+        match next {
+            Some(n) => path.push(n),
+            None => return path,
+        }
+    }
+}
+
+/// Writes line `tline` of `subway` back out in the format `load_subway_data` reads.
+///
+/// The line must have the shape a file can describe: a trunk labelled with the line's
+/// name or with several branch names at once, and branches labelled with a single
+/// name, each leaving the trunk at one station and not branching again. Names are
+/// written separated by spaces, so neither the line's nor any branch's may hold one.
+pub fn export_subway_line(subway: &Subway, tline: &str) -> Result<String, TQueryError> {
+    let fail = |&: reason: &str| {
+        TQueryError::Unexportable { line: tline.to_string(), reason: reason.to_string() }
+    };
+
+    // both directions of a segment are one entry, keyed by (lower id, higher id, label)
+    let mut segments: BTreeMap<(StationId, StationId, String), Option<usize>> = BTreeMap::new();
+    for (from, cs) in subway.connections.iter().enumerate() {
+        for c in cs.iter().filter(|c| c.info.line.as_slice() == tline) {
+            let key = (cmp::min(from, c.to), cmp::max(from, c.to), c.info.branch.clone());
+            if !segments.contains_key(&key) { segments.insert(key, c.minutes); }
+        }
+    }
+    if segments.is_empty() { return Err(TQueryError::NoSuchLine { query: tline.to_string() }); }
+
+    // label -> stations adjacent along segments with that label
+    let mut adjacent: BTreeMap<&str, BTreeMap<StationId, Vec<StationId>>> = BTreeMap::new();
+    for &(a, b, ref label) in segments.keys() {
+        if !adjacent.contains_key(label.as_slice()) {
+            adjacent.insert(label.as_slice(), BTreeMap::new());
+        }
+        let by_station = adjacent.get_mut(label.as_slice()).unwrap();
+        for &(x, y) in [(a, b), (b, a)].iter() {
+            if !by_station.contains_key(&x) { by_station.insert(x, vec![]); }
+            by_station.get_mut(&x).unwrap().push(y);
+        }
+    }
+    let touches_other = |&: stn: StationId, label: &str| -> bool {
+        adjacent.iter().any(|(l, by_station)| *l != label && by_station.contains_key(&stn))
+    };
+
+    // branch name -> (station it leaves the trunk at, its stations in order)
+    let mut branches: BTreeMap<&str, (StationId, Vec<StationId>)> = BTreeMap::new();
+    for (label, by_station) in adjacent.iter() {
+        if *label == tline || label.contains(" ") { continue; }
+        let ends: Vec<StationId> = by_station.iter().filter(|&(_, ns)| ns.len() == 1)
+                                             .map(|(s, _)| *s).collect();
+        let attached: Vec<StationId> = by_station.keys().filter(|s| touches_other(**s, *label))
+                                                 .map(|s| *s).collect();
+        if ends.len() != 2 || attached.len() != 1 || !ends.contains(&attached[0]) { continue; }
+        let path = path_from(by_station, attached[0]);
+        if path.len() != by_station.len() { continue; }
+        branches.insert(*label, (attached[0], path.into_iter().skip(1).collect()));
+    }
+
+    // trunk segments are the rest, and must form a single path
+    let mut trunk_adjacent: BTreeMap<StationId, Vec<StationId>> = BTreeMap::new();
+    let mut trunk_segments: HashMap<(StationId, StationId), (&str, Option<usize>)> = HashMap::new();
+    for (&(a, b, ref label), minutes) in segments.iter() {
+        if branches.contains_key(label.as_slice()) { continue; }
+        if trunk_segments.insert((a, b), (label.as_slice(), *minutes)).is_some() {
+            return Err(fail("trunk stations are connected more than once"));
+        }
+        for &(x, y) in [(a, b), (b, a)].iter() {
+            if !trunk_adjacent.contains_key(&x) { trunk_adjacent.insert(x, vec![]); }
+            trunk_adjacent.get_mut(&x).unwrap().push(y);
+        }
+    }
+    // a label on the trunk is read back as the branches named by its words
+    if tline.contains(" ") { return Err(fail("the line's name has a space in it")); }
+    for &(label, _) in trunk_segments.values() {
+        if label != tline && !label.split(' ').all(|b| branches.contains_key(b)) {
+            return Err(fail(format!("branch `{}` cannot be named in a .dat file",
+                                    label).as_slice()));
+        }
+    }
+    let trunk: Vec<StationId> = if trunk_adjacent.is_empty() {
+        let points: BTreeSet<StationId> = branches.values().map(|&(p, _)| p).collect();
+        if points.len() != 1 { return Err(fail("branches leave from stations with no trunk")); }
+        points.into_iter().collect()
+    } else {
+        let ends: Vec<StationId> = trunk_adjacent.iter().filter(|&(_, ns)| ns.len() == 1)
+                                                 .map(|(s, _)| *s).collect();
+        if ends.len() != 2 || trunk_adjacent.values().any(|ns| ns.len() > 2) {
+            return Err(fail("the trunk is not a single path"));
+        }
+        let path = path_from(&trunk_adjacent, ends[0]);
+        if path.len() != trunk_adjacent.len() {
+            return Err(fail("the trunk is not a single path"));
+        }
+        path
+    };
+    if branches.values().any(|&(p, _)| !trunk.contains(&p)) {
+        return Err(fail("a branch leaves from another branch"));
+    }
+
+    let mut out: String = String::new();
+    let header: Vec<&str> = branches.keys().map(|b| *b).collect();
+    if header.is_empty() {
+        out.push_str(format!("--- {}\n", tline).as_slice());
     } else {
-        Ok(())
+        out.push_str(format!("--- {}\n", header.connect(" ")).as_slice());
+    }
+
+    let mut label: &str = tline;
+    let mut in_branch: bool = false;
+    for (i, stn_id) in trunk.iter().enumerate() {
+        let mut minutes: Option<usize> = None;
+        if i > 0 {
+            let prev: StationId = trunk[i - 1];
+            let &(segment_label, m) = trunk_segments.get(&(cmp::min(prev, *stn_id),
+                                                             cmp::max(prev, *stn_id))).unwrap();
+            if in_branch || segment_label != label {
+                out.push_str(format!("--- {}\n", segment_label).as_slice());
+                label = segment_label;
+                in_branch = false;
+            }
+            minutes = m;
+        }
+        out.push_str(format!("{}{}\n", station_entry(subway, *stn_id),
+                             minutes_suffix(minutes)).as_slice());
+
+        for (name, &(point, ref stations)) in branches.iter() {
+            if point != *stn_id { continue; }
+            out.push_str(format!("---- {}\n", name).as_slice());
+            let mut prev: StationId = point;
+            for s in stations.iter() {
+                let key = (cmp::min(prev, *s), cmp::max(prev, *s), name.to_string());
+                let m: Option<usize> = *segments.get(&key).unwrap();
+                out.push_str(format!("\t{}{}\n", station_entry(subway, *s),
+                                     minutes_suffix(m)).as_slice());
+                prev = *s;
+            }
+            in_branch = true;
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod data_tests {
+    use std::cmp;
+    use std::io::{BufferedReader, MemReader};
+
+    use super::{load_subway_data, load_subway_data_checked};
+    use super::{load_transfer_data, load_coordinate_data, load_alias_data};
+    use super::load_schedule_data;
+    use super::{unlinked_station_warnings, export_subway_line};
+    use super::super::Subway;
+    use super::super::schedule::Closure;
+    use error::TQueryError;
+
+    fn reader(data: &str) -> BufferedReader<MemReader> {
+        BufferedReader::new(MemReader::new(data.as_bytes().to_vec()))
+    }
+
+    #[test]
+    fn test_load_subway_data() {
+        let mut subway = Subway::new();
+        let data = "--- X Y\nA\nB\n---- X\n\tC\n---- Y\n\tD\n\n";
+        assert!(load_subway_data(&mut subway, reader(data), "foo").is_ok());
+        assert_eq!(subway.size(), 4);
+        let b_id = *subway.get_station_id("B").unwrap();
+        let d_id = *subway.get_station_id("D").unwrap();
+        assert_eq!(subway.get_connection(b_id, d_id).unwrap().info.branch.as_slice(), "Y");
+    }
+
+    #[test]
+    fn test_load_segment_times() {
+        let mut subway = Subway::new();
+        let data = "--- X Y\nA\nB | 3\n---- X\n\tC [X] | 4\n---- Y\n\tD\n";
+        assert!(load_subway_data(&mut subway, reader(data), "foo").is_ok());
+        let a_id = *subway.get_station_id("A").unwrap();
+        let b_id = *subway.get_station_id("B").unwrap();
+        let c_id = *subway.get_station_id("C (X)").unwrap();
+        let d_id = *subway.get_station_id("D").unwrap();
+        assert_eq!(subway.get_connection(a_id, b_id).unwrap().minutes, Some(3));
+        assert_eq!(subway.get_connection(c_id, b_id).unwrap().cost, 4);
+        assert_eq!(subway.get_connection(b_id, d_id).unwrap().minutes, None);
+        assert_eq!(subway.get_connection(b_id, d_id).unwrap().cost, 1);
+
+        let bad = load_subway_data(&mut Subway::new(), reader("--- x\nA | 2\nB | two\n"), "x");
+        assert_eq!(bad.unwrap_err().code(), "data_format");
+    }
+
+    #[test]
+    fn test_load_distinct_stations() {
+        let mut subway = Subway::new();
+        let data = "--- X Y\nA\n---- X\n\tB [X]\n---- Y\n\tB [Y]\n\tC\n";
+        assert!(load_subway_data(&mut subway, reader(data), "foo").is_ok());
+        let bx_id = *subway.get_station_id("B (X)").unwrap();
+        let by_id = *subway.get_station_id("B (Y)").unwrap();
+        assert!(bx_id != by_id);
+        assert_eq!(subway.get_station_name(bx_id), subway.get_station_name(by_id));
+    }
+
+    #[test]
+    fn test_load_subway_data_collects_problems() {
+        let mut subway = Subway::new();
+        let data = "--- X Y Z\n  A\nB\n---- X\n\tC\n\tC\n---- W\n\tD\n---- Y\n--- Q\nE\n";
+        let problems = load_subway_data_checked(&mut subway, reader(data), "foo", "foo.dat")
+                           .unwrap_err();
+        let lines: Vec<usize> = problems.iter().map(|p| match *p {
+            TQueryError::DataFormat { ref file, line, .. } => {
+                assert_eq!(file.as_slice(), "foo.dat");
+                line
+            },
+            _ => panic!("unexpected error: {}", p),
+        }).collect();
+        // stray indent, duplicate C, undeclared W, empty Y, unknown Q, Z never appears
+        assert_eq!(lines, vec![2, 6, 7, 9, 10, 1]);
+        assert_eq!(subway.size(), 0);
+
+        let empty = load_subway_data(&mut subway, reader(""), "foo");
+        assert_eq!(empty.unwrap_err().code(), "data_format");
+    }
+
+    #[test]
+    fn test_load_transfer_data() {
+        let mut subway = Subway::new();
+        load_subway_data(&mut subway, reader("--- x\nA\nHaymarket Station\n"), "x").unwrap();
+        load_subway_data(&mut subway, reader("--- y\nHaymarket\nB\n"), "y").unwrap();
+        assert_eq!(unlinked_station_warnings(&subway).len(), 1);
+
+        let bad = load_transfer_data(&mut subway, reader("Haymarket Stn | x\n"), "t.dat");
+        assert_eq!(bad.unwrap_err().len(), 2);
+
+        let data = "# interchanges\nHaymarket Station = Haymarket | 4 | 2\n";
+        assert!(load_transfer_data(&mut subway, reader(data), "t.dat").is_ok());
+        let h_id = *subway.get_station_id("Haymarket Station").unwrap();
+        assert_eq!(subway.get_station_id("Haymarket"), Some(&h_id));
+        assert_eq!(subway.transfers.get(&h_id).unwrap().cost, Some(4));
+        assert!(unlinked_station_warnings(&subway).is_empty());
+    }
+
+    #[test]
+    fn test_load_alias_data() {
+        let mut subway = Subway::new();
+        let data = "--- x\nDowntown Crossing Station\nCharles/MGH Station\n";
+        load_subway_data(&mut subway, reader(data), "x").unwrap();
+
+        let bad = "DTX\nGC = Government Center\n = Charles/MGH Station\n\
+                   MGH = Charles/MGH Station\nMGH = Downtown Crossing Station\n";
+        assert_eq!(load_alias_data(&mut subway, reader(bad), "a.dat").unwrap_err().len(), 4);
+        assert!(subway.aliases.is_empty());
+
+        let data = "# aliases\nDTX, Downtown = Downtown Crossing Station\n\
+                    MGH, Mass General = Charles/MGH Station\n";
+        assert!(load_alias_data(&mut subway, reader(data), "a.dat").is_ok());
+        let dtx_id = *subway.get_station_id("Downtown Crossing Station").unwrap();
+        assert_eq!(subway.find_station("dtx").unwrap(), dtx_id);
+        assert_eq!(subway.find_station("Mass. General").unwrap(),
+                   *subway.get_station_id("Charles/MGH Station").unwrap());
+    }
+
+    #[test]
+    fn test_load_schedule_data() {
+        let mut subway = Subway::new();
+        let data = "--- y z\nA\n---- y\n\tB\n---- z\n\tC\n";
+        load_subway_data(&mut subway, reader(data), "x").unwrap();
+
+        let bad = "station D | 2015-01-24 05:00 | 2015-01-26 03:00\n\
+                   branch w | 2015-01-24 05:00 | 2015-01-26 03:00\n\
+                   station A | 2015-01-26 03:00 | 2015-01-24 05:00\n\
+                   station A | saturday | sunday\n\
+                   station A\n";
+        assert_eq!(load_schedule_data(&mut subway, reader(bad), "s.dat").unwrap_err().len(), 5);
+        assert!(subway.schedule.is_empty());
+
+        let data = "# weekend work\nsegment A to B | 2015-01-24 05:00 | 2015-01-26 03:00\n\
+                    branch Y | 2015-01-31 05:00 | 2015-02-02 03:00\n";
+        assert!(load_schedule_data(&mut subway, reader(data), "s.dat").is_ok());
+        assert_eq!(subway.schedule.len(), 2);
+        assert_eq!(subway.schedule[1].closure, Closure::Branch("Y".to_string()));
+    }
+
+    #[test]
+    fn test_load_coordinate_data() {
+        let mut subway = Subway::new();
+        load_subway_data(&mut subway, reader("--- x\nA\nB\n"), "x").unwrap();
+
+        let bad = "A | 42.3 | -71.1\nC | 1 | 2\nB | 95 | 0\nB | north\n";
+        assert_eq!(load_coordinate_data(&mut subway, reader(bad), "c.dat").unwrap_err().len(), 3);
+        assert!(subway.locations.is_empty());
+
+        let data = "# locations\nA | 42.3 | -71.1\n\nB|42.4|-71.2\n";
+        assert!(load_coordinate_data(&mut subway, reader(data), "c.dat").is_ok());
+        let b_id = *subway.get_station_id("B").unwrap();
+        let b = subway.get_location(b_id).unwrap();
+        assert_eq!((b.lat, b.lon), (42.4, -71.2));
+    }
+
+    #[test]
+    fn test_export_subway_line() {
+        let mut subway = Subway::new();
+        let data = "--- X Y Z\nA\nB | 2\n---- Z\n\tE\n--- X Y\nC\n---- X\n\tD [X] | 3\n\
+                    ---- Y\n\tF\n\tG\n";
+        load_subway_data(&mut subway, reader(data), "foo").unwrap();
+        assert_eq!(export_subway_line(&subway, "foo").unwrap().as_slice(), data);
+        assert!(export_subway_line(&subway, "bar").is_err());
+    }
+
+    #[test]
+    fn test_export_subway_line_gtfs_names() {
+        // GTFS names branches after where their trips end, spaces and all
+        let mut subway = Subway::new();
+        let ids: Vec<usize> = ["A", "B", "C"].iter().map(|s| subway.add_station(*s)).collect();
+        for pair in ids.windows(2) {
+            for &(f, t) in [(pair[0], pair[1]), (pair[1], pair[0])].iter() {
+                subway.add_connection(f, t, "red", "Alewife Station 2");
+                subway.add_connection(f, t, "Red Line", "Red Line");
+            }
+        }
+        assert_eq!(format!("{}", export_subway_line(&subway, "red").unwrap_err()).as_slice(),
+                   "cannot export line red: branch `Alewife Station 2` cannot be named in a \
+                    .dat file");
+        assert_eq!(export_subway_line(&subway, "Red Line").unwrap_err().code(), "unexportable");
+        assert_eq!(export_subway_line(&subway, "bar").unwrap_err().code(), "no_such_line");
+    }
+
+    /// A small linear congruential generator, so the property test below is repeatable.
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 = (self.0 * 1103515245 + 12345) % 2147483648;
+            ((self.0 >> 16) as usize) % n
+        }
+    }
+
+    fn random_station(rng: &mut Lcg, tline: &str, count: &mut usize, indent: bool,
+                      timed: bool) -> String {
+        *count += 1;
+        let mut entry: String = format!("{}{} s{}", if indent { "\t" } else { "" }, tline, *count);
+        if rng.below(5) == 0 { entry.push_str(" [k]"); }
+        if timed && rng.below(2) == 0 { entry.push_str(format!(" | {}", 1 + rng.below(9)).as_slice()); }
+        entry.push_str("\n");
+        entry
+    }
+
+    /// Writes a random but valid `.dat` file for line `tline`.
+    fn random_line(rng: &mut Lcg, tline: &str) -> String {
+        let names: Vec<String> = range(0, rng.below(5)).map(|b| format!("{}{}", tline, b))
+                                                       .collect();
+        let mut out: String = if names.is_empty() {
+            format!("--- {}\n", tline)
+        } else {
+            format!("--- {}\n", names.connect(" "))
+        };
+        let mut count: usize = 0;
+        let mut next_branch: usize = 0;
+        loop {
+            // at least two stations up front, so every line has a connection
+            let extra: usize = if count == 0 { 1 } else { 0 };
+            for _ in range(0, 1 + extra + rng.below(3)) {
+                let timed = count > 0;
+                out.push_str(random_station(rng, tline, &mut count, false, timed).as_slice());
+            }
+            if next_branch == names.len() { break; }
+
+            for _ in range(0, 1 + rng.below(cmp::min(2, names.len() - next_branch))) {
+                out.push_str(format!("---- {}\n", names[next_branch]).as_slice());
+                next_branch += 1;
+                for _ in range(0, 1 + rng.below(3)) {
+                    out.push_str(random_station(rng, tline, &mut count, true, true).as_slice());
+                }
+            }
+            if next_branch == names.len() { break; }
+
+            // the trunk carries on as the line, or as every branch still to come
+            let rest: &[String] = names.slice_from(next_branch);
+            if rest.len() > 1 && rng.below(2) == 0 {
+                out.push_str(format!("--- {}\n", rest.connect(" ")).as_slice());
+            } else {
+                out.push_str(format!("--- {}\n", tline).as_slice());
+            }
+        }
+        out
+    }
+
+    fn segments(subway: &Subway) -> Vec<(String, String, String, String, Option<usize>)> {
+        let mut segments = vec![];
+        for (from, cs) in subway.connections.iter().enumerate() {
+            for c in cs.iter() {
+                segments.push((subway.stations[from].clone(), subway.stations[c.to].clone(),
+                               c.info.line.clone(), c.info.branch.clone(), c.minutes));
+            }
+        }
+        segments.sort();
+        segments
+    }
+
+    #[test]
+    fn test_export_round_trip_property() {
+        let mut rng = Lcg(42);
+        for _ in range(0, 200) {
+            let mut subway = Subway::new();
+            for tline in ["p", "q"].iter() {
+                let data = random_line(&mut rng, *tline);
+                load_subway_data(&mut subway, reader(data.as_slice()), *tline).unwrap();
+            }
+
+            let mut reloaded = Subway::new();
+            for tline in ["p", "q"].iter() {
+                let exported = export_subway_line(&subway, *tline).unwrap();
+                load_subway_data(&mut reloaded, reader(exported.as_slice()), *tline).unwrap();
+                assert_eq!(export_subway_line(&reloaded, *tline).unwrap(), exported);
+            }
+
+            let mut labels = subway.stations.clone();
+            let mut reloaded_labels = reloaded.stations.clone();
+            labels.sort();
+            reloaded_labels.sort();
+            assert_eq!(labels, reloaded_labels);
+            assert_eq!(segments(&subway), segments(&reloaded));
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,192 @@
+//! Draws the network, or part of it, as a Graphviz DOT graph. Stations are coloured by
+//! line, segments are labelled with their branch and cost, and anything disabled or
+//! closed is drawn in red, dashed for connections.
+#![allow(unstable)]
+
+use error::TQueryError;
+
+use super::{Subway, StationId, Connection, WALK_LINE};
+use super::names::normalize;
+use super::route::Route;
+use super::schedule::Closures;
+
+/// The part of the network to draw.
+pub enum DotScope<'a> {
+    All,
+    /// Only the line with the given name
+    Line(&'a str),
+    /// Only the segments ridden along a route
+    Route(&'a Route),
+}
+
+/// Colours used for lines without a colour of their own.
+const PALETTE: [&'static str; 6] = ["purple", "brown", "darkcyan", "goldenrod", "magenta",
+                                     "slategray"];
+
+/// The colour to draw line `line` in: its own name if it is named after a colour, such
+/// as `Red` or `Red Line`.
+fn line_colour(subway: &Subway, line: &str) -> String {
+    let name: String = normalize(line);
+    let name: &str = if name.as_slice().ends_with(" line") {
+        name.as_slice().slice_to(name.len() - " line".len())
+    } else {
+        name.as_slice()
+    };
+    for colour in ["red", "green", "blue", "orange", "silver"].iter() {
+        if name == *colour { return colour.to_string(); }
+    }
+    let index: usize = subway.lines().iter().position(|l| l.as_slice() == line).unwrap_or(0);
+    PALETTE[index % PALETTE.len()].to_string()
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace("\\", "\\\\").replace("\"", "\\\""))
+}
+
+/// The connections to draw, one per pair of stations and branch.
+fn segments<'a>(subway: &'a Subway, scope: &DotScope) -> Vec<(StationId, &'a Connection)> {
+    let mut segments: Vec<(StationId, &Connection)> = vec![];
+    match *scope {
+        DotScope::Route(route) => {
+            for leg in route.legs.iter() {
+                let mut stations: Vec<StationId> = vec![leg.board];
+                stations.push_all(leg.stops.as_slice());
+                stations.push(leg.alight);
+                for pair in stations.windows(2) {
+                    if let Some(c) = leg.connection(subway, pair[0], pair[1]) {
+                        segments.push((pair[0], c));
+                    }
+                }
+            }
+        },
+        _ => {
+            for (from, cs) in subway.connections.iter().enumerate() {
+                for c in cs.iter() {
+                    if c.info.line.as_slice() == WALK_LINE { continue; }
+                    if let DotScope::Line(line) = *scope {
+                        if c.info.line.as_slice() != line { continue; }
+                    }
+                    // the way back is drawn by the same edge, unless it does not exist
+                    let reverse = subway.get_connections(c.to).map(|back| {
+                        back.iter().any(|b| b.to == from && b.info == c.info)
+                    }).unwrap_or(false);
+                    if from > c.to && reverse { continue; }
+                    segments.push((from, c));
+                }
+            }
+        },
+    }
+    segments
+}
+
+/// Draws the part of `subway` given by `scope` as an undirected DOT graph, or says why
+/// there is nothing to draw if `scope` names no line.
+pub fn export_dot(subway: &Subway, scope: DotScope) -> Result<String, TQueryError> {
+    let line: String = match scope {
+        DotScope::Line(line) => try!(subway.find_line(line)),
+        _ => String::new(),
+    };
+    let scope: DotScope = match scope {
+        DotScope::Line(_) => DotScope::Line(line.as_slice()),
+        scope => scope,
+    };
+    let segments = segments(subway, &scope);
+    let closed: Closures = Closures::at(subway, subway.clock.now());
+
+    // every station drawn, with the line it is coloured by
+    let mut stations: Vec<(StationId, &str)> = vec![];
+    for &(from, c) in segments.iter() {
+        for id in [from, c.to].iter() {
+            if !stations.iter().any(|&(s, _)| s == *id) {
+                stations.push((*id, c.info.line.as_slice()));
+            }
+        }
+    }
+    stations.sort();
+
+    let mut out: String = "graph subway {\n    node [style=filled, fontcolor=white];\n".to_string();
+    for &(id, line) in stations.iter() {
+        let name: &str = subway.get_station(id).map(|s| s.as_slice()).unwrap_or("?");
+        if closed.stations.contains(&id) {
+            out.push_str(format!("    {} [label={}, fillcolor=white, fontcolor=red, color=red, \
+                                  penwidth=3];\n",
+                                 id, quote(format!("{} (disabled)", name).as_slice())).as_slice());
+        } else {
+            out.push_str(format!("    {} [label={}, fillcolor={}];\n",
+                                 id, quote(name), line_colour(subway, line)).as_slice());
+        }
+    }
+    for &(from, c) in segments.iter() {
+        let label = quote(format!("{} ({})", c.info.branch, c.cost).as_slice());
+        let open: bool = !closed.stations.contains(&from) && !closed.stations.contains(&c.to);
+        if open && closed.is_open(from, c) {
+            out.push_str(format!("    {} -- {} [label={}, color={}];\n", from, c.to, label,
+                                 line_colour(subway, c.info.line.as_slice())).as_slice());
+        } else {
+            out.push_str(format!("    {} -- {} [label={}, color=red, style=dashed];\n",
+                                 from, c.to, label).as_slice());
+        }
+    }
+    out.push_str("}\n");
+    Ok(out)
+}
+
+#[cfg(test)]
+mod dot_tests {
+    use super::{export_dot, DotScope};
+    use super::super::Subway;
+    use super::super::route::find_route;
+    use super::super::schedule::{Closure, Disruption, FixedClock, parse_time};
+
+    fn sample() -> Subway {
+        let mut subway = Subway::new();
+        let a_id = subway.add_station("A");
+        let b_id = subway.add_station("B \"the\" stop");
+        let c_id = subway.add_station("C");
+        for &(f, t, line) in [(a_id, b_id, "Red"), (b_id, c_id, "Northline")].iter() {
+            subway.add_connection(f, t, line, line);
+            subway.add_connection(t, f, line, line);
+        }
+        subway
+    }
+
+    #[test]
+    fn test_export_dot() {
+        let mut subway = sample();
+        subway.disable_station(2);
+        let dot = export_dot(&subway, DotScope::All).unwrap();
+        assert!(dot.starts_with("graph subway {\n"));
+        assert!(dot.contains("    0 [label=\"A\", fillcolor=red];\n"));
+        assert!(dot.contains("    1 [label=\"B \\\"the\\\" stop\", fillcolor=red];\n"));
+        assert!(dot.contains("    0 -- 1 [label=\"Red (1)\", color=red];\n"));
+        assert!(dot.contains("    1 -- 2 [label=\"Northline (1)\", color=red, style=dashed];\n"));
+        assert!(dot.contains("C (disabled)"));
+        assert_eq!(dot.lines().filter(|l| l.contains(" -- ")).count(), 2);
+    }
+
+    #[test]
+    fn test_export_dot_scheduled() {
+        let mut subway = sample();
+        subway.clock = Box::new(FixedClock(parse_time("2015-01-25 12:00").unwrap()));
+        subway.schedule.push(Disruption { closure: Closure::Line("Red".to_string()),
+                                          start: parse_time("2015-01-24 05:00").unwrap(),
+                                          end: parse_time("2015-01-26 03:00").unwrap() });
+        let dot = export_dot(&subway, DotScope::All).unwrap();
+        assert!(dot.contains("    0 -- 1 [label=\"Red (1)\", color=red, style=dashed];\n"));
+    }
+
+    #[test]
+    fn test_export_dot_scoped() {
+        let subway = sample();
+        let line = export_dot(&subway, DotScope::Line("northline")).unwrap();
+        assert!(!line.contains("0 [label"));
+        assert!(line.contains("    1 -- 2 [label=\"Northline (1)\", color=purple];\n"));
+
+        let route = find_route(&subway, 0, 1).unwrap();
+        let route_dot = export_dot(&subway, DotScope::Route(&route)).unwrap();
+        assert!(route_dot.contains("    0 -- 1 "));
+        assert!(!route_dot.contains("2 [label"));
+        assert_eq!(export_dot(&subway, DotScope::Line("Southline")).unwrap_err().code(),
+                   "no_such_line");
+    }
+}
@@ -0,0 +1,219 @@
+//! Describes the network, or a route through it, as GeoJSON for drawing on a map.
+//! Only stations with a known location are drawn, see `Subway::locations`.
+//!
+//! * the network is a `FeatureCollection` holding a `MultiLineString` for every line,
+//!   one line string per segment, followed by a `Point` for every station
+//! * a route is a `FeatureCollection` holding a `LineString` for every leg, followed by
+//!   a `Point` for every station ridden through; a leg through stations with no known
+//!   location is split around them, and pieces left with a single station are not drawn
+//!
+//! Line features carry `line` (and `branch` for legs) properties, station features
+//! carry `id`, `name`, `label` and `disabled`.
+#![allow(unstable)]
+
+use std::collections::BTreeMap;
+
+use rustc_serialize::json::{self, Json};
+
+use super::{Subway, StationId};
+use super::route::Route;
+
+type Object = BTreeMap<String, Json>;
+
+/// `[lon, lat]`, the GeoJSON position of station `stn_id`, if its location is known.
+fn position(subway: &Subway, stn_id: StationId) -> Option<Json> {
+    subway.get_location(stn_id).map(|c| Json::Array(vec![Json::F64(c.lon), Json::F64(c.lat)]))
+}
+
+fn string(s: &str) -> Json {
+    Json::String(s.to_string())
+}
+
+fn feature(kind: &str, coordinates: Json, properties: Object) -> Json {
+    let mut geometry: Object = BTreeMap::new();
+    geometry.insert("type".to_string(), string(kind));
+    geometry.insert("coordinates".to_string(), coordinates);
+
+    let mut feature: Object = BTreeMap::new();
+    feature.insert("type".to_string(), string("Feature"));
+    feature.insert("geometry".to_string(), Json::Object(geometry));
+    feature.insert("properties".to_string(), Json::Object(properties));
+    Json::Object(feature)
+}
+
+fn station_feature(subway: &Subway, stn_id: StationId) -> Option<Json> {
+    position(subway, stn_id).map(|point| {
+        let mut properties: Object = BTreeMap::new();
+        properties.insert("id".to_string(), Json::U64(stn_id as u64));
+        properties.insert("name".to_string(), string(subway.names[stn_id].as_slice()));
+        properties.insert("label".to_string(), string(subway.stations[stn_id].as_slice()));
+        properties.insert("disabled".to_string(),
+                          Json::Boolean(subway.disabled_stations.contains(&stn_id)));
+        feature("Point", point, properties)
+    })
+}
+
+fn collection(features: Vec<Json>) -> String {
+    let mut collection: Object = BTreeMap::new();
+    collection.insert("type".to_string(), string("FeatureCollection"));
+    collection.insert("features".to_string(), Json::Array(features));
+    json::encode(&Json::Object(collection))
+}
+
+/// Describes every line of `subway` and every station on them as GeoJSON.
+pub fn export_geojson(subway: &Subway) -> String {
+    let mut features: Vec<Json> = vec![];
+    for tline in subway.lines().iter() {
+        let mut segments: Vec<Json> = vec![];
+        for (from, cs) in subway.connections.iter().enumerate() {
+            for c in cs.iter().filter(|c| c.info.line == *tline) {
+                // both directions of a segment are drawn by the same line string
+                let reverse = subway.get_connections(c.to).map(|back| {
+                    back.iter().any(|b| b.to == from && b.info.line == *tline)
+                }).unwrap_or(false);
+                if from > c.to && reverse { continue; }
+                let segment = (position(subway, from), position(subway, c.to));
+                if let (Some(a), Some(b)) = segment {
+                    let segment = Json::Array(vec![a, b]);
+                    if !segments.contains(&segment) { segments.push(segment); }
+                }
+            }
+        }
+        let mut properties: Object = BTreeMap::new();
+        properties.insert("line".to_string(), string(tline.as_slice()));
+        features.push(feature("MultiLineString", Json::Array(segments), properties));
+    }
+    for stn_id in range(0, subway.size()) {
+        // merged stations are drawn once, as the station they were merged into
+        if !subway.is_station(stn_id) { continue; }
+        if let Some(station) = station_feature(subway, stn_id) { features.push(station); }
+    }
+    collection(features)
+}
+
+/// Describes `route` through `subway` as GeoJSON.
+pub fn export_route_geojson(subway: &Subway, route: &Route) -> String {
+    let mut features: Vec<Json> = vec![];
+    for leg in route.legs.iter() {
+        let mut stations: Vec<StationId> = vec![leg.board];
+        stations.push_all(leg.stops.as_slice());
+        stations.push(leg.alight);
+
+        // runs of located stations, as a line string needs at least two positions
+        let mut pieces: Vec<Vec<Json>> = vec![vec![]];
+        for id in stations.iter() {
+            match position(subway, *id) {
+                Some(point) => pieces.last_mut().unwrap().push(point),
+                None => pieces.push(vec![]),
+            }
+        }
+        for points in pieces.into_iter().filter(|p| p.len() >= 2) {
+            let mut properties: Object = BTreeMap::new();
+            properties.insert("line".to_string(), string(leg.info.line.as_slice()));
+            properties.insert("branch".to_string(), string(leg.info.branch.as_slice()));
+            features.push(feature("LineString", Json::Array(points), properties));
+        }
+    }
+    for stn_id in route.stations().into_iter() {
+        if let Some(station) = station_feature(subway, stn_id) { features.push(station); }
+    }
+    collection(features)
+}
+
+#[cfg(test)]
+mod geojson_tests {
+    use rustc_serialize::json::Json;
+
+    use super::{export_geojson, export_route_geojson};
+    use super::super::{Subway, Coord};
+    use super::super::route::find_route;
+
+    fn sample() -> Subway {
+        let mut subway = Subway::new();
+        let a_id = subway.add_station("A");
+        let b_id = subway.add_station("B");
+        let c_id = subway.add_station("C");
+        let d_id = subway.add_station("D");
+        for &(f, t, line) in [(a_id, b_id, "x"), (b_id, c_id, "x"), (b_id, d_id, "y")].iter() {
+            subway.add_connection(f, t, line, line);
+            subway.add_connection(t, f, line, line);
+        }
+        subway.set_location(a_id, Coord::new(42.0, -71.0).unwrap());
+        subway.set_location(b_id, Coord::new(42.5, -71.5).unwrap());
+        subway.set_location(c_id, Coord::new(43.0, -72.0).unwrap());
+        subway
+    }
+
+    fn features(geojson: &str) -> Vec<Json> {
+        let parsed = Json::from_str(geojson).unwrap();
+        assert_eq!(parsed.find("type").and_then(|t| t.as_string()), Some("FeatureCollection"));
+        parsed.find("features").and_then(|f| f.as_array()).unwrap().clone()
+    }
+
+    fn geometry<'a>(feature: &'a Json) -> (&'a str, &'a Json) {
+        let geometry = feature.find("geometry").unwrap();
+        (geometry.find("type").and_then(|t| t.as_string()).unwrap(),
+         geometry.find("coordinates").unwrap())
+    }
+
+    #[test]
+    fn test_export_geojson() {
+        let features = features(export_geojson(&sample()).as_slice());
+        // lines x and y, then stations A, B and C; D has no location
+        assert_eq!(features.len(), 5);
+
+        let (kind, segments) = geometry(&features[0]);
+        assert_eq!(kind, "MultiLineString");
+        assert_eq!(segments.as_array().unwrap().len(), 2);
+        assert_eq!(features[0].find_path(&["properties", "line"]).and_then(|l| l.as_string()),
+                   Some("x"));
+        // line y has no segment with both ends located
+        assert_eq!(geometry(&features[1]).1.as_array().unwrap().len(), 0);
+
+        let (kind, point) = geometry(&features[2]);
+        assert_eq!(kind, "Point");
+        assert_eq!(point.as_array().unwrap()[0].as_f64(), Some(-71.0));
+    }
+
+    #[test]
+    fn test_export_route_geojson() {
+        let subway = sample();
+        let route = find_route(&subway, 0, 2).unwrap();
+        let features = features(export_route_geojson(&subway, &route).as_slice());
+        assert_eq!(features.len(), 4);
+
+        let (kind, points) = geometry(&features[0]);
+        assert_eq!(kind, "LineString");
+        assert_eq!(points.as_array().unwrap().len(), 3);
+        assert_eq!(features[0].find_path(&["properties", "branch"]).and_then(|b| b.as_string()),
+                   Some("x"));
+    }
+
+    #[test]
+    fn test_export_route_geojson_unlocated() {
+        let mut subway = Subway::new();
+        let ids: Vec<usize> = ["A", "B", "C", "D", "E"].iter().map(|s| {
+            subway.add_station(*s)
+        }).collect();
+        for pair in ids.windows(2) {
+            subway.add_connection(pair[0], pair[1], "x", "x");
+            subway.add_connection(pair[1], pair[0], "x", "x");
+        }
+        for &id in [ids[0], ids[1], ids[3], ids[4]].iter() {
+            subway.set_location(id, Coord::new(42.0, -71.0 - id as f64).unwrap());
+        }
+
+        // split around C, rather than drawn straight across it
+        let route = find_route(&subway, ids[0], ids[4]).unwrap();
+        let split = features(export_route_geojson(&subway, &route).as_slice());
+        let lines: Vec<&Json> = split.iter().filter(|f| geometry(*f).0 == "LineString")
+                                        .collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines.iter().all(|f| geometry(*f).1.as_array().unwrap().len() == 2));
+
+        // D is left on its own past C, too short to draw
+        let route = find_route(&subway, ids[0], ids[3]).unwrap();
+        let short = features(export_route_geojson(&subway, &route).as_slice());
+        assert_eq!(short.iter().filter(|f| geometry(*f).0 == "LineString").count(), 1);
+    }
+}
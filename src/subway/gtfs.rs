@@ -0,0 +1,460 @@
+//! Builds a `Subway` out of a GTFS static feed on local disk, i.e. a directory holding
+//! `stops.txt`, `routes.txt`, `trips.txt`, `stop_times.txt` and optionally
+//! `transfers.txt`.
+//!
+//! * every GTFS route becomes a line, named by its short name (or long name, or id)
+//! * every distinct stop pattern of a route becomes a branch, named after its terminus,
+//!   unless the route only has the one pattern, in which case the branch is the line
+//! * platforms are folded into their parent station, which is located by its
+//!   `stop_lat` and `stop_lon`, or by those of the platform if it has none
+//! * segment times are the average over all trips of a pattern, from `stop_times.txt`
+//! * transfers within a station make it an interchange, walking the longest
+//!   `min_transfer_time` given for it; transfers between stations become connections on
+//!   the `WALK_LINE`, taking that long to walk, or `DEFAULT_WALK_MINUTES` if not given
+//!
+//! Only rail routes are loaded, see `RAIL_ROUTE_TYPES`.
+#![allow(unstable)]
+
+use std::cmp;
+use std::io;
+use std::io::fs::PathExtensions;
+use std::collections::{BTreeMap, HashMap};
+
+use error::TQueryError;
+
+use super::{Subway, StationId, Coord, WALK_LINE};
+
+/// GTFS `route_type`s loaded: light rail, subway, rail and monorail.
+pub const RAIL_ROUTE_TYPES: [&'static str; 4] = ["0", "1", "2", "12"];
+
+/// Minutes taken walking between stations with a transfer that gives no
+/// `min_transfer_time`.
+pub const DEFAULT_WALK_MINUTES: usize = 5;
+
+/// A GTFS file, one row of fields per record along with its line number.
+struct Table {
+    file: String,
+    columns: HashMap<String, usize>,
+    rows: Vec<(usize, Vec<String>)>,
+}
+
+impl Table {
+    fn read(dir: &Path, name: &str, required: &[&str]) -> Result<Table, TQueryError> {
+        let path: Path = dir.join(name);
+        let file: String = format!("{}", path.display());
+        let mut content = match io::File::open(&path) {
+            Ok(f) => io::BufferedReader::new(f),
+            Err(e) => return Err(gtfs_error(file.as_slice(), 0, format!("unable to open: {}", e))),
+        };
+
+        let mut columns: HashMap<String, usize> = HashMap::new();
+        let mut rows: Vec<(usize, Vec<String>)> = vec![];
+        for (i, l) in content.lines().enumerate() {
+            let line: String = match l {
+                Ok(line) => line,
+                Err(e) => return Err(gtfs_error(file.as_slice(), i + 1,
+                                                format!("unable to read line: {}", e))),
+            };
+            let line: &str = line.trim_left_matches('\u{feff}').trim_right();
+            if i == 0 {
+                for (col, name) in split_csv(line).into_iter().enumerate() {
+                    columns.insert(name, col);
+                }
+            } else if !line.is_empty() {
+                rows.push((i + 1, split_csv(line)));
+            }
+        }
+
+        for column in required.iter() {
+            if !columns.contains_key(*column) {
+                return Err(gtfs_error(file.as_slice(), 1,
+                                      format!("missing required column `{}`", column)));
+            }
+        }
+        Ok(Table { file: file, columns: columns, rows: rows })
+    }
+
+    /// The value of `column` in `row`, or "" if the column is absent.
+    fn get<'a>(&self, row: &'a Vec<String>, column: &str) -> &'a str {
+        self.columns.get(column).and_then(|col| row.get(*col))
+                                .map(|f| f.as_slice())
+                                .unwrap_or("")
+    }
+}
+
+fn gtfs_error(file: &str, line: usize, reason: String) -> TQueryError {
+    TQueryError::DataFormat { file: file.to_string(), line: line, reason: reason }
+}
+
+/// Splits a CSV record into its fields, honouring double quoted fields.
+fn split_csv(line: &str) -> Vec<String> {
+    let mut fields: Vec<String> = vec![];
+    let mut field: String = String::new();
+    let mut quoted: bool = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '"' {
+            if quoted && chars.peek() == Some(&'"') {
+                field.push('"');
+                chars.next();
+            } else {
+                quoted = !quoted;
+            }
+        } else if c == ',' && !quoted {
+            fields.push(field.trim().to_string());
+            field = String::new();
+        } else {
+            field.push(c);
+        }
+    }
+    fields.push(field.trim().to_string());
+    fields
+}
+
+/// Parses a GTFS `HH:MM:SS` time, where hours may run past 24, into seconds.
+fn parse_time(time: &str) -> Option<usize> {
+    let parts: Vec<Option<usize>> = time.split(':').map(|p| p.parse::<usize>()).collect();
+    if parts.len() != 3 { return None; }
+    match (parts[0], parts[1], parts[2]) {
+        (Some(h), Some(m), Some(s)) => Some(h * 3600 + m * 60 + s),
+        _ => None,
+    }
+}
+
+/// Maps GTFS stops onto `Subway` stations, creating stations as they are first used.
+struct Stations {
+    /// stop id -> id of the stop standing for its station, i.e. its parent if it has one
+    parent_of: HashMap<String, String>,
+    /// stop id -> stop name
+    names: HashMap<String, String>,
+    /// stop id -> stop location, for stops with one
+    coords: HashMap<String, Coord>,
+    /// station stop id -> station id
+    ids: HashMap<String, StationId>,
+    /// station name -> station stop id first loaded under that name
+    names_used: HashMap<String, String>,
+}
+
+impl Stations {
+    fn new(stops: &Table) -> Stations {
+        let mut parent_of: HashMap<String, String> = HashMap::new();
+        let mut names: HashMap<String, String> = HashMap::new();
+        let mut coords: HashMap<String, Coord> = HashMap::new();
+        for &(_, ref row) in stops.rows.iter() {
+            let id: &str = stops.get(row, "stop_id");
+            let parent: &str = stops.get(row, "parent_station");
+            let station: &str = if parent.is_empty() { id } else { parent };
+            parent_of.insert(id.to_string(), station.to_string());
+            names.insert(id.to_string(), stops.get(row, "stop_name").to_string());
+            let lat = stops.get(row, "stop_lat").parse::<f64>();
+            let lon = stops.get(row, "stop_lon").parse::<f64>();
+            if let Some(coord) = lat.and_then(|lat| lon.and_then(|lon| Coord::new(lat, lon))) {
+                coords.insert(id.to_string(), coord);
+            }
+        }
+        Stations { parent_of: parent_of, names: names, coords: coords, ids: HashMap::new(),
+                   names_used: HashMap::new() }
+    }
+
+    /// The station for `stop_id`, added to `subway` if it is not there yet.
+    fn resolve(&mut self, subway: &mut Subway, stop_id: &str) -> Option<StationId> {
+        let station: String = match self.parent_of.get(stop_id) {
+            Some(s) => s.clone(),
+            None => return None,
+        };
+        if let Some(id) = self.ids.get(&station) { return Some(*id); }
+
+        let name: String = self.names.get(&station).or(self.names.get(stop_id))
+                                     .map(|n| n.clone())
+                                     .unwrap_or(station.clone());
+        let id: StationId = match self.names_used.get(&name) {
+            // another station by that name, tell this one apart by its stop id
+            Some(other) if *other != station => subway.add_distinct_station(name.as_slice(),
+                                                                          station.as_slice()),
+            _ => subway.add_station(name.as_slice()),
+        };
+        if !self.names_used.contains_key(&name) {
+            self.names_used.insert(name, station.clone());
+        }
+        if let Some(coord) = self.coords.get(&station).or(self.coords.get(stop_id)) {
+            subway.set_location(id, *coord);
+        }
+        self.ids.insert(station, id);
+        Some(id)
+    }
+
+    /// The station for `stop_id` if it has already been added.
+    fn existing(&self, stop_id: &str) -> Option<StationId> {
+        self.parent_of.get(stop_id).and_then(|s| self.ids.get(s)).map(|id| *id)
+    }
+}
+
+/// The stations a route's trips stop at, in order, and the minutes between them summed
+/// over every trip following that pattern.
+struct Pattern {
+    stations: Vec<StationId>,
+    minutes: Vec<usize>,
+    timed_trips: Vec<usize>,
+}
+
+impl Pattern {
+    /// Average minutes between the stations of segment `i`, if any trip timed it.
+    fn average_minutes(&self, i: usize) -> Option<usize> {
+        match self.timed_trips[i] {
+            0 => None,
+            n => Some((self.minutes[i] + n / 2) / n),
+        }
+    }
+}
+
+/// Whether `short` runs along a contiguous part of `long`, as a short turn does.
+fn runs_within(short: &[StationId], long: &[StationId]) -> bool {
+    short.len() < long.len() && long.windows(short.len()).any(|w| w == short)
+}
+
+/// Loads the GTFS feed in `dir` into `subway`.
+pub fn load_gtfs(subway: &mut Subway, dir: &Path) -> Result<(), TQueryError> {
+    let stops = try!(Table::read(dir, "stops.txt", &["stop_id", "stop_name"]));
+    let routes = try!(Table::read(dir, "routes.txt", &["route_id", "route_type"]));
+    let trips = try!(Table::read(dir, "trips.txt", &["route_id", "trip_id"]));
+    let stop_times = try!(Table::read(dir, "stop_times.txt",
+                                      &["trip_id", "stop_id", "stop_sequence"]));
+
+    // route id -> line name
+    let mut lines: BTreeMap<String, String> = BTreeMap::new();
+    for &(_, ref row) in routes.rows.iter() {
+        if !RAIL_ROUTE_TYPES.contains(&routes.get(row, "route_type")) { continue; }
+        let route_id: &str = routes.get(row, "route_id");
+        let name: &str = [routes.get(row, "route_short_name"),
+                          routes.get(row, "route_long_name"),
+                          route_id].iter().map(|n| *n).find(|n| !n.is_empty()).unwrap();
+        lines.insert(route_id.to_string(), name.to_string());
+    }
+
+    // trip id -> (route id, whether it runs in direction 1)
+    let mut trip_routes: HashMap<String, (String, bool)> = HashMap::new();
+    for &(_, ref row) in trips.rows.iter() {
+        let route_id: &str = trips.get(row, "route_id");
+        if !lines.contains_key(route_id) { continue; }
+        trip_routes.insert(trips.get(row, "trip_id").to_string(),
+                           (route_id.to_string(), trips.get(row, "direction_id") == "1"));
+    }
+
+    // trip id -> [(stop sequence, stop id, arrival, departure, line number)]
+    let mut trip_stops: HashMap<String, Vec<(usize, String, Option<usize>, Option<usize>, usize)>> =
+        HashMap::new();
+    for &(line_no, ref row) in stop_times.rows.iter() {
+        let trip_id: &str = stop_times.get(row, "trip_id");
+        if !trip_routes.contains_key(trip_id) { continue; }
+        let seq: usize = match stop_times.get(row, "stop_sequence").parse::<usize>() {
+            Some(seq) => seq,
+            None => return Err(gtfs_error(stop_times.file.as_slice(), line_no,
+                                          "stop_sequence is not a number".to_string())),
+        };
+        let arrival = parse_time(stop_times.get(row, "arrival_time"));
+        let departure = parse_time(stop_times.get(row, "departure_time")).or(arrival);
+        let stop = (seq, stop_times.get(row, "stop_id").to_string(), arrival, departure, line_no);
+        if let Some(stops) = trip_stops.get_mut(trip_id) {
+            stops.push(stop);
+            continue;
+        }
+        trip_stops.insert(trip_id.to_string(), vec![stop]);
+    }
+
+    let mut stations = Stations::new(&stops);
+    let mut patterns: BTreeMap<String, Vec<Pattern>> = BTreeMap::new();
+    let mut trip_ids: Vec<&String> = trip_stops.keys().collect();
+    trip_ids.sort();
+    for trip_id in trip_ids.into_iter() {
+        let &(ref route_id, reversed) = trip_routes.get(trip_id).unwrap();
+        let mut stops = trip_stops.get(trip_id).unwrap().clone();
+        stops.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut ids: Vec<StationId> = vec![];
+        let mut minutes: Vec<Option<usize>> = vec![];
+        let mut last_departure: Option<usize> = None;
+        for &(_, ref stop_id, arrival, departure, line_no) in stops.iter() {
+            let id: StationId = match stations.resolve(subway, stop_id.as_slice()) {
+                Some(id) => id,
+                None => return Err(gtfs_error(stop_times.file.as_slice(), line_no,
+                                              format!("unknown stop `{}`", stop_id))),
+            };
+            // consecutive platforms of one station, left when the train leaves the last one
+            if ids.last() == Some(&id) {
+                last_departure = departure;
+                continue;
+            }
+            if !ids.is_empty() {
+                minutes.push(match (last_departure, arrival) {
+                    (Some(d), Some(a)) if a >= d => Some(::std::cmp::max(1, (a - d + 30) / 60)),
+                    _ => None,
+                });
+            }
+            ids.push(id);
+            last_departure = departure;
+        }
+        if ids.len() < 2 { continue; }
+        if reversed {
+            ids.reverse();
+            minutes.reverse();
+        }
+
+        if !patterns.contains_key(route_id) { patterns.insert(route_id.clone(), vec![]); }
+        let route_patterns = patterns.get_mut(route_id).unwrap();
+        let pos: usize = match route_patterns.iter().position(|p| p.stations == ids) {
+            Some(pos) => pos,
+            None => {
+                let segments: usize = ids.len() - 1;
+                route_patterns.push(Pattern { stations: ids,
+                                              minutes: range(0, segments).map(|_| 0).collect(),
+                                              timed_trips: range(0, segments).map(|_| 0).collect() });
+                route_patterns.len() - 1
+            },
+        };
+        let pattern = &mut route_patterns[pos];
+        for (i, m) in minutes.iter().enumerate() {
+            if let Some(m) = *m {
+                pattern.minutes[i] += m;
+                pattern.timed_trips[i] += 1;
+            }
+        }
+    }
+
+    for (route_id, route_patterns) in patterns.iter() {
+        let line: &str = lines.get(route_id).unwrap().as_slice();
+        // short turns run along another pattern and are not branches of their own
+        let variants: Vec<&Pattern> = route_patterns.iter().filter(|p| {
+            !route_patterns.iter().any(|other| runs_within(p.stations.as_slice(),
+                                                           other.stations.as_slice()))
+        }).collect();
+
+        let mut branches: Vec<String> = vec![];
+        for pattern in variants.iter() {
+            let branch: String = if variants.len() == 1 {
+                line.to_string()
+            } else {
+                let terminus = *pattern.stations.last().unwrap();
+                let name: String = subway.get_station_name(terminus).unwrap().clone();
+                let mut branch: String = name.clone();
+                let mut n: usize = 1;
+                while branches.contains(&branch) {
+                    n += 1;
+                    branch = format!("{} {}", name, n);
+                }
+                branch
+            };
+            for (i, pair) in pattern.stations.windows(2).enumerate() {
+                let minutes = pattern.average_minutes(i);
+                subway.add_timed_connection(pair[0], pair[1], line, branch.as_slice(), minutes);
+                subway.add_timed_connection(pair[1], pair[0], line, branch.as_slice(), minutes);
+            }
+            branches.push(branch);
+        }
+    }
+
+    if dir.join("transfers.txt").exists() {
+        let transfers = try!(Table::read(dir, "transfers.txt", &["from_stop_id", "to_stop_id"]));
+        // the longest walk given for each pair of stations, in the order first given
+        let mut walks: Vec<((StationId, StationId), Option<usize>)> = vec![];
+        for &(_, ref row) in transfers.rows.iter() {
+            // transfer type 3 means no transfer is possible
+            if transfers.get(row, "transfer_type") == "3" { continue; }
+            let from = stations.existing(transfers.get(row, "from_stop_id"));
+            let to = stations.existing(transfers.get(row, "to_stop_id"));
+            if let (Some(from), Some(to)) = (from, to) {
+                let seconds = transfers.get(row, "min_transfer_time").parse::<usize>();
+                let walk: Option<usize> = seconds.map(|s| (s + 59) / 60);
+                match walks.iter().position(|&(pair, _)| pair == (from, to)) {
+                    Some(pos) => walks[pos].1 = cmp::max(walks[pos].1, walk),
+                    None => walks.push(((from, to), walk)),
+                }
+            }
+        }
+        for &((from, to), walk) in walks.iter() {
+            if from == to {
+                subway.add_transfer(from, from, None, walk.unwrap_or(0));
+            } else {
+                // walking to another station is never free
+                let walk: usize = cmp::max(1, walk.unwrap_or(DEFAULT_WALK_MINUTES));
+                subway.add_timed_connection(from, to, WALK_LINE, WALK_LINE, Some(walk));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod gtfs_tests {
+    use std::io::{File, TempDir};
+
+    use super::{load_gtfs, split_csv, parse_time, DEFAULT_WALK_MINUTES};
+    use super::super::{Subway, WALK_LINE};
+
+    fn write(dir: &TempDir, name: &str, content: &str) {
+        File::create(&dir.path().join(name)).write_str(content).unwrap();
+    }
+
+    #[test]
+    fn test_split_csv() {
+        assert_eq!(split_csv("a, \"b,c\",\"say \"\"hi\"\"\","),
+                   vec!["a".to_string(), "b,c".to_string(), "say \"hi\"".to_string(),
+                        "".to_string()]);
+        assert_eq!(parse_time("25:01:30"), Some(90090));
+        assert_eq!(parse_time(""), None);
+    }
+
+    #[test]
+    fn test_load_gtfs() {
+        let dir = TempDir::new("t_query_gtfs").unwrap();
+        write(&dir, "stops.txt", "stop_id,stop_name,parent_station,stop_lat,stop_lon\n\
+                                  A,Alpha,,42.5,-71.5\nB,Beta,,,\nB1,Beta Northbound,B,42.25,-71\n\
+                                  C,Gamma,,,\nD,Delta,,,\n");
+        write(&dir, "routes.txt", "route_id,route_short_name,route_long_name,route_type\n\
+                                   R,red,Red Line,1\nX,99,Bus,3\n");
+        write(&dir, "trips.txt", "route_id,service_id,trip_id,direction_id\n\
+                                  R,S,T1,0\nR,S,T2,1\nR,S,T3,0\nX,S,T4,0\n");
+        write(&dir, "stop_times.txt", "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n\
+                                       T1,08:00:00,08:00:00,A,1\nT1,08:03:00,08:03:00,B1,2\n\
+                                       T1,08:05:00,08:05:00,C,3\n\
+                                       T2,09:00:00,09:00:00,C,1\nT2,09:02:00,09:02:00,B,2\n\
+                                       T2,09:05:00,09:05:00,A,3\n\
+                                       T3,10:00:00,10:00:00,A,1\nT3,10:03:00,10:03:00,B,2\n\
+                                       T3,10:05:00,10:06:00,B1,3\nT3,10:09:00,10:09:00,D,4\n\
+                                       T4,11:00:00,11:00:00,A,1\nT4,11:10:00,11:10:00,D,2\n");
+        write(&dir, "transfers.txt", "from_stop_id,to_stop_id,transfer_type,min_transfer_time\n\
+                                      B1,B,2,300\nB,B,2,120\nA,D,2,240\nA,D,2,90\nC,D,3,\n\
+                                      D,A,2,\n");
+
+        let mut subway = Subway::new();
+        load_gtfs(&mut subway, dir.path()).unwrap();
+        assert_eq!(subway.size(), 4);
+
+        let a_id = *subway.get_station_id("Alpha").unwrap();
+        let b_id = *subway.get_station_id("Beta").unwrap();
+        let d_id = *subway.get_station_id("Delta").unwrap();
+        let a_b: Vec<(&str, Option<usize>)> = subway.get_connections(a_id).unwrap().iter()
+            .filter(|c| c.to == b_id)
+            .map(|c| (c.info.branch.as_slice(), c.minutes))
+            .collect();
+        assert_eq!(a_b, vec![("Gamma", Some(3)), ("Delta", Some(3))]);
+        // timed from the last platform left, without the wait between them
+        assert_eq!(subway.get_connection(b_id, d_id).unwrap().minutes, Some(3));
+        // buses are not loaded
+        assert!(subway.get_connections(a_id).unwrap().iter().all(|c| {
+            c.info.line.as_slice() != "99"
+        }));
+        assert_eq!(subway.get_location(a_id).map(|c| (c.lat, c.lon)), Some((42.5, -71.5)));
+        assert_eq!(subway.get_location(b_id).map(|c| c.lat), Some(42.25));
+        assert!(subway.get_location(d_id).is_none());
+
+        // walking between stations keeps them apart
+        assert_eq!(subway.transfers.get(&b_id).map(|t| t.walk), Some(5));
+        assert!(subway.transfers.get(&a_id).is_none());
+        let walk = subway.get_connections(a_id).unwrap().iter()
+            .find(|c| c.to == d_id && c.info.line.as_slice() == WALK_LINE).unwrap();
+        assert_eq!(walk.minutes, Some(4));
+        assert_eq!(subway.get_connection(d_id, a_id).unwrap().minutes, Some(DEFAULT_WALK_MINUTES));
+        let c_id = *subway.get_station_id("Gamma").unwrap();
+        assert!(subway.get_connection(c_id, d_id).is_none());
+    }
+}
@@ -0,0 +1,240 @@
+//! Records every change made to the subway while running, such as disabling a station,
+//! so they can be replayed when `t_query` starts again. The journal is append-only, one
+//! change per line, as the query that made it along with when it was made:
+//!
+//! ```text
+//! 2015-01-24T05:00:00Z disable Kenmore Station
+//! 2015-01-24T05:02:00Z disable segment Kenmore Station to Blandford Street
+//! ```
+//!
+//! Stations are named by their labels, so a journal can only be replayed onto the same
+//! subway data, or data naming the same stations.
+#![allow(unstable)]
+
+use std::io;
+use std::mem;
+use std::sync::Mutex;
+
+use time;
+use time::Timespec;
+
+use error::TQueryError;
+
+use super::{Subway, StationId};
+use super::route::Query;
+use super::schedule::{FixedClock, describe_disruption};
+
+const TIME_FORMAT: &'static str = "%Y-%m-%dT%H:%M:%SZ";
+
+pub struct Journal {
+    log: Mutex<Box<Writer + Send>>,
+}
+
+impl Journal {
+    /// Creates a new `Journal` appending to `log`.
+    pub fn new(log: Box<Writer + Send>) -> Journal {
+        Journal { log: Mutex::new(log) }
+    }
+
+    /// Records `commands`, written out by `commands` for a change just made at `at`.
+    pub fn record(&self, at: Timespec, commands: &[String]) {
+        let at: String = time::at_utc(at).rfc3339();
+        let mut log = self.log.lock().unwrap();
+        for command in commands.iter() {
+            let entry: String = format!("{} {}\n", at, command);
+            if log.write_str(entry.as_slice()).is_err() || log.flush().is_err() {
+                println!("error: unable to write journal entry: {}", entry.trim());
+            }
+        }
+    }
+}
+
+/// The queries making the change `query` makes, with stations named by their labels,
+/// one per entry; none if `query` changes nothing. Stations disabled together are
+/// journaled one by one, as their labels may hold the commas separating them, and
+/// disruptions called off in full, as their place in the schedule may differ on replay.
+pub fn commands(subway: &Subway, query: &Query) -> Vec<String> {
+    let label = |&: id: StationId| -> &str {
+        subway.get_station(id).map(|s| s.as_slice()).unwrap_or("?")
+    };
+    vec![match *query {
+        Query::Enable(stn) => format!("enable {}", label(stn)),
+        Query::Disable(stn) => format!("disable {}", label(stn)),
+        Query::DisableStations(ref stns, ref incident) => {
+            return stns.iter().map(|stn| match *incident {
+                Some(ref incident) => format!("disable {} for incident {}", label(*stn), incident),
+                None => format!("disable {}", label(*stn)),
+            }).collect();
+        },
+        Query::EnableIncident(ref incident) => format!("enable incident {}", incident),
+        Query::Alias(ref alias, stn) => format!("alias {} = {}", alias, label(stn)),
+        Query::DisableSegment(a, b) => format!("disable segment {} to {}", label(a), label(b)),
+        Query::EnableSegment(a, b) => format!("enable segment {} to {}", label(a), label(b)),
+        Query::DisableBranch(ref branch) => format!("disable branch {}", branch),
+        Query::EnableBranch(ref branch) => format!("enable branch {}", branch),
+        Query::DisableLine(ref line) => format!("disable line {}", line),
+        Query::EnableLine(ref line) => format!("enable line {}", line),
+        Query::PassThrough(stn) => format!("pass through {}", label(stn)),
+        Query::StopAt(stn) => format!("stop at {}", label(stn)),
+        Query::Schedule(ref disruption) => {
+            format!("schedule {}", describe_disruption(subway, disruption))
+        },
+        Query::Unschedule(n) => match n.checked_sub(1).and_then(|i| subway.schedule.get(i)) {
+            Some(disruption) => format!("unschedule {}", describe_disruption(subway, disruption)),
+            None => return vec![],
+        },
+        Query::UnscheduleDisruption(ref disruption) => {
+            format!("unschedule {}", describe_disruption(subway, disruption))
+        },
+        Query::Route(..) | Query::RouteAt(..) | Query::ListSchedule | Query::Status => {
+            return vec![];
+        },
+    }]
+}
+
+fn journal_error(file: &str, line: usize, reason: String) -> TQueryError {
+    TQueryError::DataFormat { file: file.to_string(), line: line, reason: reason }
+}
+
+/// Replays the changes recorded in `content` onto `subway`, each as of when it was made.
+/// Every problem found is reported, naming `file` and the line it was found on, and
+/// nothing is replayed unless every entry can be read. `subway` should have no journal
+/// of its own yet, or the changes would be recorded again.
+pub fn replay<R: Reader>(subway: &mut Subway, mut content: io::BufferedReader<R>, file: &str)
+                         -> Result<(), Vec<TQueryError>> {
+    let mut problems: Vec<TQueryError> = vec![];
+    let mut changes: Vec<(usize, Timespec, Query)> = vec![];
+
+    for (i, line) in content.lines().enumerate() {
+        let line_no: usize = i + 1;
+        let line: String = match line {
+            Ok(line) => line,
+            Err(e) => return Err(vec![journal_error(file, line_no,
+                                                    format!("unable to read line: {}", e))]),
+        };
+        let entry: &str = line.as_slice().trim();
+        if entry.is_empty() { continue; }
+
+        let (at, command) = match entry.find(' ') {
+            Some(space) => (entry.slice_to(space), entry.slice_from(space + 1)),
+            None => (entry, ""),
+        };
+        let at: Timespec = match time::strptime(at, TIME_FORMAT) {
+            Ok(tm) => tm.to_timespec(),
+            Err(_) => {
+                problems.push(journal_error(file, line_no, format!("`{}` is not a time", at)));
+                continue;
+            },
+        };
+        let parsed = Query::parse(command, |&: name: &str| match subway.get_station_id(name) {
+            Some(id) => Ok(*id),
+            None => Err(TQueryError::NoSuchStation { query: name.to_string(),
+                                                     suggestions: vec![] }),
+        });
+        match parsed {
+            Ok(ref query) if query.is_mutation() => changes.push((line_no, at, query.clone())),
+            Ok(_) => problems.push(journal_error(file, line_no,
+                                                 format!("`{}` is not a change", command))),
+            Err(e) => problems.push(journal_error(file, line_no, e.describe(subway))),
+        }
+    }
+    if !problems.is_empty() { return Err(problems); }
+
+    for &(line_no, at, ref query) in changes.iter() {
+        let clock = mem::replace(&mut subway.clock, Box::new(FixedClock(at)));
+        let applied = subway.apply(query);
+        subway.clock = clock;
+        if let Err(e) = applied {
+            problems.push(journal_error(file, line_no, e.describe(subway)));
+        }
+    }
+    if !problems.is_empty() { return Err(problems); }
+    Ok(())
+}
+
+#[cfg(test)]
+mod journal_tests {
+    use std::io::{BufferedReader, ChanReader, ChanWriter, MemReader};
+    use std::sync::mpsc::channel;
+
+    use super::{Journal, replay};
+    use subway::Subway;
+    use subway::route::Query;
+    use subway::schedule::{FixedClock, parse_time, describe_disruption};
+
+    fn sample() -> Subway {
+        let mut subway = Subway::new();
+        let a_id = subway.add_station("A");
+        let b_id = subway.add_station("St. Paul Street (B)");
+        subway.add_connection(a_id, b_id, "x", "x");
+        subway.add_connection(b_id, a_id, "x", "x");
+        subway.add_station("Harvard Square, Cambridge");
+        subway
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let mut subway = sample();
+        let (send, recv) = channel();
+        subway.journal = Some(Journal::new(Box::new(ChanWriter::new(send))));
+        subway.clock = Box::new(FixedClock(parse_time("2015-01-24 05:00").unwrap()));
+        for line in ["disable St. Paul Street (B)", "from A to St. Paul Street (B)",
+                     "disable segment A to St. Paul Street (B)", "pass through A",
+                     "disable A, St. Paul Street (B) for incident 42",
+                     "disable Harvard Square, Cambridge for incident 43",
+                     "schedule branch x from 2015-01-31 05:00 until 2015-02-02 03:00",
+                     "schedule line x from 2015-02-07 05:00 until 2015-02-09 03:00",
+                     "unschedule 1"].iter() {
+            let query = Query::new(&subway, *line).unwrap();
+            subway.apply(&query).unwrap();
+        }
+        drop(subway);
+
+        let journal: String = ChanReader::new(recv).read_to_string().unwrap();
+        assert_eq!(journal.as_slice(),
+                   "2015-01-24T05:00:00Z disable St. Paul Street (B)\n\
+                    2015-01-24T05:00:00Z disable segment A to St. Paul Street (B)\n\
+                    2015-01-24T05:00:00Z pass through A\n\
+                    2015-01-24T05:00:00Z disable A for incident 42\n\
+                    2015-01-24T05:00:00Z disable St. Paul Street (B) for incident 42\n\
+                    2015-01-24T05:00:00Z disable Harvard Square, Cambridge for incident 43\n\
+                    2015-01-24T05:00:00Z schedule branch x from 2015-01-31 05:00 \
+                    until 2015-02-02 03:00\n\
+                    2015-01-24T05:00:00Z schedule line x from 2015-02-07 05:00 \
+                    until 2015-02-09 03:00\n\
+                    2015-01-24T05:00:00Z unschedule branch x from 2015-01-31 05:00 \
+                    until 2015-02-02 03:00\n");
+
+        // the schedule file loaded at startup may have changed since
+        let mut restarted = sample();
+        let planned = Query::new(&restarted, "schedule station A from 2015-03-01 05:00 \
+                                              until 2015-03-02 03:00").unwrap();
+        restarted.apply(&planned).unwrap();
+        let content = BufferedReader::new(MemReader::new(journal.into_bytes()));
+        assert!(replay(&mut restarted, content, "journal").is_ok());
+        assert!(restarted.disabled_stations.contains(&1));
+        assert_eq!(restarted.disabled_since.get(&1),
+                   Some(&parse_time("2015-01-24 05:00").unwrap()));
+        assert!(restarted.closed_segments.contains(&(0, 1)));
+        assert!(restarted.pass_through_stations.contains(&0));
+        let schedule: Vec<String> = restarted.schedule.iter().map(|d| {
+            describe_disruption(&restarted, d)
+        }).collect();
+        assert_eq!(schedule,
+                   vec!["station A from 2015-03-01 05:00 until 2015-03-02 03:00".to_string(),
+                        "line x from 2015-02-07 05:00 until 2015-02-09 03:00".to_string()]);
+        assert_eq!(restarted.incidents.get(&"42".to_string()), Some(&vec![0]));
+        assert_eq!(restarted.incidents.get(&"43".to_string()), Some(&vec![2]));
+    }
+
+    #[test]
+    fn test_replay_bad_journal() {
+        let mut subway = sample();
+        let journal = "2015-01-24T05:00:00Z disable A\nyesterday disable A\n\
+                       2015-01-24T05:00:00Z disable C\n2015-01-24T05:00:00Z status\n\
+                       2015-01-24T05:00:00Z unschedule 1\n";
+        let content = BufferedReader::new(MemReader::new(journal.as_bytes().to_vec()));
+        assert_eq!(replay(&mut subway, content, "journal").unwrap_err().len(), 3);
+        assert!(subway.disabled_stations.is_empty());
+    }
+}
@@ -1,21 +1,106 @@
 #![allow(unstable)]
 
-extern crate regex;
-
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::collections::hash_map::Entry::{Occupied, Vacant};
+use std::mem;
+
+use regex::Regex;
+
+use error::TQueryError;
+
+use self::names::{normalize, match_name, MatchKind};
+use self::route::DisabledPolicy;
+use self::schedule::{Clock, SystemClock, Closure, Disruption, format_time, describe_disruption};
+use self::journal::Journal;
+use self::route::Query;
+
+use time::Timespec;
 
 
 pub mod data;
+pub mod dot;
+pub mod geojson;
+pub mod gtfs;
+pub mod journal;
+pub mod names;
+pub mod network;
 pub mod route;
+pub mod schedule;
+
+/// Most stations suggested when no name matches.
+pub const MAX_SUGGESTIONS: usize = 3;
+
+/// Minutes a connection of unknown length is costed at when routing, so costs compare
+/// minutes with minutes.
+pub const DEFAULT_SEGMENT_MINUTES: usize = 1;
+
+/// Line, and branch, of connections walked rather than ridden between two stations.
+/// Walking is not a line of its own, so `lines` and the exports drawing lines leave it out.
+pub const WALK_LINE: &'static str = "walk";
 
 pub type StationId = usize;
 pub type Station = String;
 
 pub struct Subway {
+    /// Unique label of every station, e.g. "St. Paul Street (B)"
     pub stations: Vec<Station>,
+    /// Display name of every station, e.g. "St. Paul Street"
+    pub names: Vec<String>,
     pub station_name_id_map: HashMap<String, StationId>,
     pub connections: Vec<Vec<Connection>>,
+    pub disabled_stations: HashSet<StationId>,
+    /// When each disabled station was disabled, for those disabled while running
+    pub disabled_since: HashMap<StationId, Timespec>,
+    /// Stations each open incident holds disabled, in the order it disabled them
+    pub incidents: HashMap<String, Vec<StationId>>,
+    /// Segments closed in both directions, as pairs of adjacent stations, lowest id first
+    pub closed_segments: HashSet<(StationId, StationId)>,
+    /// Branches suspended, with the line they belong to
+    pub closed_branches: HashSet<StationInfo>,
+    /// Lines suspended altogether
+    pub closed_lines: HashSet<String>,
+    /// Stations trains run through without stopping, so riders cannot board or alight
+    pub pass_through_stations: HashSet<StationId>,
+    /// How routes treat `disabled_stations`
+    pub disabled_policy: DisabledPolicy,
+    /// Disruptions planned ahead of time, in the order they were scheduled
+    pub schedule: Vec<Disruption>,
+    /// What routes take the current time from
+    pub clock: Box<Clock + Send + Sync>,
+    /// Where changes made by `apply` are recorded, if anywhere
+    pub journal: Option<Journal>,
+    /// Declared interchanges, keyed by station
+    pub transfers: HashMap<StationId, Transfer>,
+    /// Free-form information about the network, e.g. its agency or data version
+    pub metadata: BTreeMap<String, String>,
+    /// Where stations are, for those whose location is known
+    pub locations: HashMap<StationId, Coord>,
+    /// Other names riders know stations by, e.g. "DTX", keyed by normalized alias
+    pub aliases: HashMap<String, StationId>,
+}
+
+impl PartialEq for Subway {
+    /// Stations without connections compare equal whether or not they have an entry in
+    /// `connections`, and disabled stations whenever they were disabled. Only the network
+    /// is compared: closed segments, branches and lines, pass-through stations, the
+    /// disabled policy, the schedule and incidents are left to the journal and the
+    /// schedule file, as they are in network descriptions.
+    fn eq(&self, other: &Subway) -> bool {
+        let none: Vec<Connection> = vec![];
+        let count: usize = cmp::max(self.connections.len(), other.connections.len());
+        self.stations == other.stations &&
+            self.names == other.names &&
+            self.station_name_id_map == other.station_name_id_map &&
+            self.disabled_stations == other.disabled_stations &&
+            self.transfers == other.transfers &&
+            self.metadata == other.metadata &&
+            self.locations == other.locations &&
+            self.aliases == other.aliases &&
+            range(0, count).all(|i| {
+                self.connections.get(i).unwrap_or(&none) == other.connections.get(i).unwrap_or(&none)
+            })
+    }
 }
 
 #[derive(Eq, PartialEq, Hash, Clone, Show)]
@@ -24,22 +109,64 @@ pub struct Connection {
     pub cost: usize,
     pub active: bool,
     pub info: StationInfo,
+    /// Travel time in minutes, if known; `cost` is the same or 1 when unknown
+    pub minutes: Option<usize>,
 }
 
-#[derive(Eq, PartialEq, Hash, Clone, Show)]
+#[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Show)]
 pub struct StationInfo {
     pub line: String,
     pub branch: String,
 }
 
+/// What it takes to change lines at an interchange station.
+#[derive(Copy, Eq, PartialEq, Clone, Show)]
+pub struct Transfer {
+    /// Penalty for changing lines here, the routing default if `None`
+    pub cost: Option<usize>,
+    /// Minutes spent walking between platforms
+    pub walk: usize,
+}
+
+/// A location on the globe, in degrees.
+#[derive(Copy, PartialEq, Clone, Show)]
+pub struct Coord {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl Coord {
+    /// Creates a `Coord`, if `lat` and `lon` lie within their valid ranges.
+    pub fn new(lat: f64, lon: f64) -> Option<Coord> {
+        if lat < -90.0 || lat > 90.0 || lon < -180.0 || lon > 180.0 { return None; }
+        Some(Coord { lat: lat, lon: lon })
+    }
+}
+
 impl Subway {
 
     /// Creates a new `Subway`
     pub fn new() -> Subway {
         Subway {
             stations: vec![],
+            names: vec![],
             station_name_id_map: HashMap::new(),
             connections: vec![],
+            disabled_stations: HashSet::new(),
+            disabled_since: HashMap::new(),
+            incidents: HashMap::new(),
+            closed_segments: HashSet::new(),
+            closed_branches: HashSet::new(),
+            closed_lines: HashSet::new(),
+            pass_through_stations: HashSet::new(),
+            disabled_policy: DisabledPolicy::Forbid,
+            schedule: vec![],
+            clock: Box::new(SystemClock),
+            journal: None,
+            transfers: HashMap::new(),
+            metadata: BTreeMap::new(),
+            locations: HashMap::new(),
+            aliases: HashMap::new(),
         }
     }
 
@@ -47,14 +174,27 @@ impl Subway {
     /// If the station already exists in the graph, returns the id of the
     /// existing station.
     pub fn add_station(&mut self, station: &str) -> StationId {
-        match self.station_name_id_map.entry(station.to_string()) {
+        self.add_labelled_station(station.to_string(), station)
+    }
+
+    /// Adds a station that shares its display name `name` with other stations but is
+    /// told apart from them by `qualifier`, e.g. the branch it is on. The station is
+    /// labelled "`name` (`qualifier`)". If a station with that label already exists,
+    /// returns its id.
+    pub fn add_distinct_station(&mut self, name: &str, qualifier: &str) -> StationId {
+        self.add_labelled_station(format!("{} ({})", name, qualifier), name)
+    }
+
+    fn add_labelled_station(&mut self, label: String, name: &str) -> StationId {
+        match self.station_name_id_map.entry(label.clone()) {
             Occupied(ent) => {
                 let stn_id: &StationId = ent.get();
                 return *stn_id;
             },
             Vacant(ent) => {
                 let stn_id: StationId = self.stations.len();
-                self.stations.push(station.to_string());
+                self.stations.push(label);
+                self.names.push(name.to_string());
                 ent.insert(stn_id);
                 return stn_id;
             }
@@ -68,21 +208,142 @@ impl Subway {
     /// for that station.
     pub fn add_connection(&mut self, f: StationId, t: StationId, l: &str, b: &str)
                           -> (StationId, usize) {
+        self.add_timed_connection(f, t, l, b, None)
+    }
+
+    /// Like `add_connection`, but the connection takes `minutes` to travel if known.
+    /// Connections of unknown length cost `DEFAULT_SEGMENT_MINUTES`, though their
+    /// `minutes` stay unknown.
+    pub fn add_timed_connection(&mut self, f: StationId, t: StationId, l: &str, b: &str,
+                                minutes: Option<usize>) -> (StationId, usize) {
         let i = StationInfo { line: l.to_string(), branch: b.to_string() };
-        let c = Connection { to: t, cost: 1, active: true, info: i };
-        if self.connections.len() > f {
-            if !self.connections[f].contains(&c) {
-                self.connections[f].push(c);
-                return (f, self.connections[f].len()-1);
-            } else {
-                let pos = self.connections[f].position_elem(&c).unwrap();
-                return (f, pos);
+        // stations without connections of their own may precede `f`
+        while self.connections.len() <= f { self.connections.push(vec![]); }
+        if let Some(pos) = self.connections[f].iter().position(|c| c.to == t && c.info == i) {
+            return (f, pos);
+        }
+        let c = Connection { to: t, cost: minutes.unwrap_or(DEFAULT_SEGMENT_MINUTES),
+                             active: true, info: i, minutes: minutes };
+        self.connections[f].push(c);
+        return (f, self.connections[f].len()-1);
+    }
+
+    /// Declares the station with id `a` an interchange where changing lines costs `cost`
+    /// (the routing default if `None`) plus `walk` minutes. If `b` is a different
+    /// station, it is taken to be the same interchange under another name and is merged
+    /// into `a`. Returns the id of the interchange.
+    pub fn add_transfer(&mut self, a: StationId, b: StationId, cost: Option<usize>, walk: usize)
+                        -> StationId {
+        self.merge_stations(a, b);
+        self.transfers.insert(a, Transfer { cost: cost, walk: walk });
+        a
+    }
+
+    /// Moves every connection from and to station `other` over to station `keep`, along
+    /// with whatever else is known of it, after which looking `other` up by name yields
+    /// `keep`. The id of `other` stays taken but no longer names a station.
+    fn merge_stations(&mut self, keep: StationId, other: StationId) {
+        if keep == other { return; }
+        while self.connections.len() <= keep { self.connections.push(vec![]); }
+
+        let moved: Vec<Connection> = match self.get_connections_mut(other) {
+            Some(cs) => mem::replace(cs, vec![]),
+            None => vec![],
+        };
+        for c in moved.into_iter() {
+            if c.to != keep && !self.connections[keep].contains(&c) {
+                self.connections[keep].push(c);
             }
-        } else {
-            let cs: Vec<Connection> = vec![c];
-            self.connections.push(cs);
-            return (f, 0);
         }
+        for connections in self.connections.iter_mut() {
+            for c in connections.iter_mut() {
+                if c.to == other { c.to = keep; }
+            }
+        }
+        self.connections[keep].retain(|c| c.to != keep);
+
+        let label: String = self.stations[other].clone();
+        self.station_name_id_map.insert(label, keep);
+        if let Some(t) = self.transfers.remove(&other) {
+            self.transfers.insert(keep, t);
+        }
+        if let Some(coord) = self.locations.remove(&other) {
+            if !self.locations.contains_key(&keep) { self.locations.insert(keep, coord); }
+        }
+        for stn_id in self.aliases.values_mut() {
+            if *stn_id == other { *stn_id = keep; }
+        }
+
+        let since: Option<Timespec> = self.disabled_since.remove(&other);
+        if self.disabled_stations.remove(&other) && self.set_station_state(keep, false) {
+            if let Some(since) = since { self.disabled_since.insert(keep, since); }
+        }
+        if self.pass_through_stations.remove(&other) { self.pass_through_stations.insert(keep); }
+        for stations in self.incidents.values_mut() {
+            for stn_id in stations.iter_mut() {
+                if *stn_id == other { *stn_id = keep; }
+            }
+            stations.dedup();
+        }
+        let segments: Vec<(StationId, StationId)> =
+            self.closed_segments.iter().map(|segment| *segment).collect();
+        self.closed_segments.clear();
+        for (a, b) in segments.into_iter() {
+            let a = if a == other { keep } else { a };
+            let b = if b == other { keep } else { b };
+            if a != b { self.closed_segments.insert((cmp::min(a, b), cmp::max(a, b))); }
+        }
+        for disruption in self.schedule.iter_mut() {
+            match disruption.closure {
+                Closure::Station(ref mut stn_id) => {
+                    if *stn_id == other { *stn_id = keep; }
+                },
+                Closure::Segment(ref mut a, ref mut b) => {
+                    if *a == other { *a = keep; }
+                    if *b == other { *b = keep; }
+                },
+                _ => {},
+            }
+        }
+    }
+
+    /// Whether `stn_id` is the id of a station, one not merged into another.
+    pub fn is_station(&self, stn_id: StationId) -> bool {
+        match self.get_station(stn_id) {
+            Some(label) => self.get_station_id(label.as_slice()) == Some(&stn_id),
+            None => false,
+        }
+    }
+
+    /// Lets the station with id `stn_id` be found by `alias` as well as by its own name.
+    /// Aliases are compared like station names, ignoring case and punctuation, and
+    /// take precedence over them. An alias already standing for another station is left
+    /// as it is.
+    pub fn add_alias(&mut self, alias: &str, stn_id: StationId) -> Result<(), TQueryError> {
+        let key: String = normalize(alias);
+        match self.aliases.get(&key) {
+            Some(&taken) if taken != stn_id => {
+                return Err(TQueryError::AliasTaken { alias: alias.to_string(), station: taken });
+            },
+            _ => {},
+        }
+        self.aliases.insert(key, stn_id);
+        Ok(())
+    }
+
+    /// Possibly retrieves the station `alias` stands for.
+    pub fn get_alias(&self, alias: &str) -> Option<&StationId> {
+        self.aliases.get(&normalize(alias))
+    }
+
+    /// Records that the station with id `stn_id` is at `coord`.
+    pub fn set_location(&mut self, stn_id: StationId, coord: Coord) {
+        self.locations.insert(stn_id, coord);
+    }
+
+    /// Possibly retrieves the location of the station with the given id.
+    pub fn get_location(&self, stn_id: StationId) -> Option<&Coord> {
+        self.locations.get(&stn_id)
     }
 
     /// Possibly retrieves the name of the station with the given id.
@@ -90,34 +351,83 @@ impl Subway {
         self.stations.get(stn_id)
     }
 
+    /// Possibly retrieves the display name of the station with the given id. Unlike
+    /// `get_station`, distinct stations sharing a name are not told apart.
+    pub fn get_station_name(&self, stn_id: StationId) -> Option<&String> {
+        self.names.get(stn_id)
+    }
+
     /// Possibly retrieves the id for the given station.
     pub fn get_station_id(&self, station: &str) -> Option<&StationId>{
         self.station_name_id_map.get(station)
     }
 
-    /// Attempts to find the station with the given name.
-    pub fn find_station(&self, stn: &str) -> Result<StationId, String> {
-        let stn_re = regex::Regex::new(stn).unwrap();
-        // build list of stations whose name matches `stn`
-        let stns = self.station_name_id_map.iter()
-                                           .filter(|&(ref s, _)| stn_re.is_match(s.as_slice()))
-                                           .map(|(_, id)| *id);
-
-        let mut lo_stns: Vec<StationId> = stns.collect();
-
-        if lo_stns.len() > 1 {
-            let mut emsg = "disambiguate your destination:".to_string();
-            for s in lo_stns.iter() {
-                let station = self.get_station(*s).unwrap();
-                emsg.push_str(" "); emsg.push_str(station.as_slice());
+    /// Every station whose name or alias matches `query`, ignoring case, punctuation
+    /// and any "Station" suffix, along with how well it matches. Best matches come
+    /// first.
+    pub fn search_stations(&self, query: &str) -> Vec<(StationId, MatchKind)> {
+        let query: String = normalize(query);
+        let mut found: Vec<(StationId, MatchKind)> = vec![];
+        for (label, id) in self.station_name_id_map.iter() {
+            let name: String = normalize(label.as_slice());
+            if let Some(kind) = match_name(query.as_slice(), name.as_slice()) {
+                found.push((*id, kind));
             }
-            return Err(emsg);
-        } else if lo_stns.len() == 0 {
-            return Err(format!("No such station: {}\n", stn));
         }
+        for (alias, id) in self.aliases.iter() {
+            if let Some(kind) = match_name(query.as_slice(), alias.as_slice()) {
+                found.push((*id, kind));
+            }
+        }
+        found.sort_by(|a, b| (a.1, a.0).cmp(&(b.1, b.0)));
+        // merged stations are found under each of their names, keep the best
+        let mut best: Vec<(StationId, MatchKind)> = vec![];
+        for &(id, kind) in found.iter() {
+            if !best.iter().any(|&(b, _)| b == id) { best.push((id, kind)); }
+        }
+        best
+    }
+
+    /// Attempts to find the station with the given name or alias. Aliases are checked
+    /// first; after them exact matches are preferred over prefix matches, and those over
+    /// substring matches; close misspellings are only ever offered as suggestions. A name
+    /// written `/pattern/` is instead matched as a regular expression against station
+    /// labels.
+    pub fn find_station(&self, stn: &str) -> Result<StationId, TQueryError> {
+        if let Some(id) = self.get_alias(stn) { return Ok(*id); }
+        if stn.len() > 1 && stn.starts_with("/") && stn.ends_with("/") {
+            return self.find_station_by_pattern(stn.slice(1, stn.len() - 1));
+        }
+        let found: Vec<(StationId, MatchKind)> = self.search_stations(stn);
+        let best: Option<MatchKind> = found.get(0).map(|&(_, kind)| kind);
+        let lo_stns: Vec<StationId> = match best {
+            Some(MatchKind::Fuzzy(_)) | None => {
+                let suggestions = found.iter().take(MAX_SUGGESTIONS).map(|&(id, _)| id).collect();
+                return Err(TQueryError::NoSuchStation { query: stn.to_string(),
+                                                        suggestions: suggestions });
+            },
+            Some(kind) => found.iter().filter(|&&(_, k)| k == kind).map(|&(id, _)| id).collect(),
+        };
+        single_station(stn, lo_stns)
+    }
 
-        let stn_id = lo_stns.pop();
-        Ok(stn_id.unwrap())
+    /// Attempts to find the station whose label matches the regular expression
+    /// `pattern`.
+    fn find_station_by_pattern(&self, pattern: &str) -> Result<StationId, TQueryError> {
+        let stn_re = match Regex::new(pattern) {
+            Ok(re) => re,
+            Err(e) => return Err(TQueryError::InvalidPattern { pattern: pattern.to_string(),
+                                                               reason: format!("{:?}", e) }),
+        };
+        let lo_stns: Vec<StationId> = self.station_name_id_map.iter()
+                                          .filter(|&(ref s, _)| stn_re.is_match(s.as_slice()))
+                                          .map(|(_, id)| *id)
+                                          .collect();
+        if lo_stns.is_empty() {
+            return Err(TQueryError::NoSuchStation { query: format!("/{}/", pattern),
+                                                    suggestions: vec![] });
+        }
+        single_station(format!("/{}/", pattern).as_slice(), lo_stns)
     }
 
     /// Possibly retrieves the list of `Connection`s for the given station.
@@ -151,12 +461,34 @@ impl Subway {
         None
     }
 
+    /// Returns the name of every line in the graph, sorted, leaving out `WALK_LINE`.
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines: Vec<String> = vec![];
+        for connections in self.connections.iter() {
+            for c in connections.iter() {
+                if c.info.line.as_slice() == WALK_LINE { continue; }
+                if !lines.contains(&c.info.line) { lines.push(c.info.line.clone()); }
+            }
+        }
+        lines.sort();
+        lines
+    }
+
     /// Returns the number of stations in the graph
     pub fn size(&self) -> usize { self.stations.len() }
 
     /// Sets connections from and to the station with the given id 
-    /// to the state given by `active`
-    fn set_station_state(&mut self, stn_id: StationId, active: bool) {
+    /// to the state given by `active`, returning whether the station was not already in it
+    fn set_station_state(&mut self, stn_id: StationId, active: bool) -> bool {
+        let changed: bool = if active {
+            self.disabled_since.remove(&stn_id);
+            self.disabled_stations.remove(&stn_id)
+        } else {
+            self.disabled_stations.insert(stn_id)
+        };
+        if changed && !active {
+            self.disabled_since.insert(stn_id, self.clock.now());
+        }
         let mut inbound_stations: Vec<StationId> = vec![];
         // set outbound connections to `active`
         if let Some(outbound_connections) = self.get_connections_mut(stn_id) {
@@ -170,6 +502,7 @@ impl Subway {
                 c.active = active;
             }
         }
+        changed
     }
 
     /// Disables the station with the given id, meaning all connections *from* the station with
@@ -177,21 +510,269 @@ impl Subway {
     pub fn disable_station(&mut self, stn_id: StationId) { self.set_station_state(stn_id, false); }
 
     /// Enables the station with the given id, meaning all connections *from* the station with
-    /// id `stn_id` are marked `active: true` as well as all connections *to* `stn_id`.
-    pub fn enable_station(&mut self, stn_id: StationId) { self.set_station_state(stn_id, true); }
+    /// id `stn_id` are marked `active: true` as well as all connections *to* `stn_id`. No
+    /// incident holds the station any longer.
+    pub fn enable_station(&mut self, stn_id: StationId) {
+        for stations in self.incidents.values_mut() {
+            stations.retain(|stn| *stn != stn_id);
+        }
+        self.set_station_state(stn_id, true);
+    }
+
+    /// Disables every station in `stn_ids` at once, for `incident` if given. An incident
+    /// holds the stations it disabled, along with those it names that another incident
+    /// already holds, so `enable_incident` can reopen exactly those. Disabling more
+    /// stations for an open incident adds them to it.
+    pub fn disable_stations(&mut self, stn_ids: &[StationId], incident: Option<&str>) {
+        let mut held: Vec<StationId> = vec![];
+        for stn_id in stn_ids.iter() {
+            let by_incident: bool = self.incidents.values().any(|stns| stns.contains(stn_id));
+            if self.set_station_state(*stn_id, false) || by_incident { held.push(*stn_id); }
+        }
+        if let Some(incident) = incident {
+            let incident: String = incident.to_string();
+            let mut stations: Vec<StationId> = self.incidents.remove(&incident).unwrap_or(vec![]);
+            for stn_id in held.into_iter() {
+                if !stations.contains(&stn_id) { stations.push(stn_id); }
+            }
+            self.incidents.insert(incident, stations);
+        }
+    }
+
+    /// Closes the incident called `incident`, enabling the stations it holds unless
+    /// another open incident holds them too.
+    pub fn enable_incident(&mut self, incident: &str) -> Result<(), TQueryError> {
+        let stations: Vec<StationId> = match self.incidents.remove(&incident.to_string()) {
+            Some(stations) => stations,
+            None => return Err(TQueryError::NoSuchIncident { incident: incident.to_string() }),
+        };
+        for stn_id in stations.iter() {
+            if !self.incidents.values().any(|stns| stns.contains(stn_id)) {
+                self.set_station_state(*stn_id, true);
+            }
+        }
+        Ok(())
+    }
+
+    /// The open incidents holding the station with the given id, sorted.
+    pub fn incidents_holding(&self, stn_id: StationId) -> Vec<String> {
+        let mut incidents: Vec<String> = self.incidents.iter().filter(|&(_, held)| {
+            held.contains(&stn_id)
+        }).map(|(incident, _)| incident.clone()).collect();
+        incidents.sort();
+        incidents
+    }
+
+    /// Closes or reopens the segment between adjacent stations `a` and `b`, in both
+    /// directions and on every line running between them.
+    fn set_segment_state(&mut self, a: StationId, b: StationId, active: bool)
+                         -> Result<(), TQueryError> {
+        try!(self.check_segment(a, b));
+        let segment = (cmp::min(a, b), cmp::max(a, b));
+        if active {
+            self.closed_segments.remove(&segment);
+        } else {
+            self.closed_segments.insert(segment);
+        }
+        Ok(())
+    }
+
+    /// Fails unless stations `a` and `b` are next to each other on some line.
+    fn check_segment(&self, a: StationId, b: StationId) -> Result<(), TQueryError> {
+        if self.get_connection(a, b).is_none() && self.get_connection(b, a).is_none() {
+            return Err(TQueryError::NoSuchSegment { from: a, to: b });
+        }
+        Ok(())
+    }
+
+    /// Closes the segment between adjacent stations `a` and `b`, e.g. for track work.
+    pub fn disable_segment(&mut self, a: StationId, b: StationId) -> Result<(), TQueryError> {
+        self.set_segment_state(a, b, false)
+    }
+
+    /// Reopens the segment between adjacent stations `a` and `b`.
+    pub fn enable_segment(&mut self, a: StationId, b: StationId) -> Result<(), TQueryError> {
+        self.set_segment_state(a, b, true)
+    }
+
+    /// Every line & branch whose branch is named `branch`, regardless of case.
+    fn find_branches(&self, branch: &str) -> Result<Vec<StationInfo>, TQueryError> {
+        let wanted: String = normalize(branch);
+        let mut found: Vec<StationInfo> = vec![];
+        for connections in self.connections.iter() {
+            for c in connections.iter() {
+                if normalize(c.info.branch.as_slice()) == wanted && !found.contains(&c.info) {
+                    found.push(c.info.clone());
+                }
+            }
+        }
+        if found.is_empty() { return Err(TQueryError::NoSuchBranch { query: branch.to_string() }); }
+        Ok(found)
+    }
+
+    /// Suspends every service on the branch named `branch`, e.g. "Mattapan".
+    pub fn disable_branch(&mut self, branch: &str) -> Result<(), TQueryError> {
+        for info in try!(self.find_branches(branch)).into_iter() {
+            self.closed_branches.insert(info);
+        }
+        Ok(())
+    }
+
+    /// Restores service on the branch named `branch`.
+    pub fn enable_branch(&mut self, branch: &str) -> Result<(), TQueryError> {
+        for info in try!(self.find_branches(branch)).iter() {
+            self.closed_branches.remove(info);
+        }
+        Ok(())
+    }
+
+    /// The name of the line called `line`, regardless of case.
+    fn find_line(&self, line: &str) -> Result<String, TQueryError> {
+        let wanted: String = normalize(line);
+        match self.lines().into_iter().find(|l| normalize(l.as_slice()) == wanted) {
+            Some(l) => Ok(l),
+            None => Err(TQueryError::NoSuchLine { query: line.to_string() }),
+        }
+    }
+
+    /// Suspends every service on the line called `line`.
+    pub fn disable_line(&mut self, line: &str) -> Result<(), TQueryError> {
+        let line: String = try!(self.find_line(line));
+        self.closed_lines.insert(line);
+        Ok(())
+    }
+
+    /// Restores service on the line called `line`.
+    pub fn enable_line(&mut self, line: &str) -> Result<(), TQueryError> {
+        let line: String = try!(self.find_line(line));
+        self.closed_lines.remove(&line);
+        Ok(())
+    }
+
+    /// Has trains run through the station with the given id without stopping.
+    pub fn pass_through_station(&mut self, stn_id: StationId) {
+        self.pass_through_stations.insert(stn_id);
+    }
+
+    /// Has trains stop at the station with the given id again.
+    pub fn stop_at_station(&mut self, stn_id: StationId) {
+        self.pass_through_stations.remove(&stn_id);
+    }
+
+    /// Schedules `disruption`, as long as what it closes exists and it ends after it
+    /// starts.
+    pub fn add_disruption(&mut self, disruption: Disruption) -> Result<(), TQueryError> {
+        try!(self.check_disruption(&disruption));
+        self.schedule.push(disruption);
+        Ok(())
+    }
+
+    /// Fails unless what `disruption` closes exists and it ends after it starts.
+    fn check_disruption(&self, disruption: &Disruption) -> Result<(), TQueryError> {
+        if disruption.end <= disruption.start {
+            return Err(TQueryError::InvalidTime { time: format_time(disruption.end),
+                                                  reason: "ends before it starts".to_string() });
+        }
+        match disruption.closure {
+            Closure::Station(stn) if self.get_station(stn).is_none() => {
+                return Err(TQueryError::NoSuchStation { query: stn.to_string(),
+                                                        suggestions: vec![] });
+            },
+            Closure::Station(_) => {},
+            Closure::Segment(a, b) => try!(self.check_segment(a, b)),
+            Closure::Branch(ref branch) => { try!(self.find_branches(branch.as_slice())); },
+            Closure::Line(ref line) => { try!(self.find_line(line.as_slice())); },
+        }
+        Ok(())
+    }
+
+    /// Removes and returns the `n`th scheduled disruption, counting from 1.
+    pub fn remove_disruption(&mut self, n: usize) -> Result<Disruption, TQueryError> {
+        if n < 1 || n > self.schedule.len() {
+            return Err(TQueryError::InvalidChoice { choice: n.to_string(),
+                                                    count: self.schedule.len() });
+        }
+        Ok(self.schedule.remove(n - 1))
+    }
+
+    /// Removes the scheduled disruption the same as `disruption`.
+    pub fn cancel_disruption(&mut self, disruption: &Disruption) -> Result<(), TQueryError> {
+        match self.schedule.iter().position(|d| d == disruption) {
+            Some(pos) => {
+                self.schedule.remove(pos);
+                Ok(())
+            },
+            None => Err(TQueryError::NotScheduled {
+                disruption: describe_disruption(self, disruption)
+            }),
+        }
+    }
+
+    /// Makes the change `query` asks for, recording it in the journal if there is one.
+    /// Queries that only ask about the subway change nothing.
+    pub fn apply(&mut self, query: &Query) -> Result<(), TQueryError> {
+        // written out before the change, while what `query` refers to is still there
+        let commands: Vec<String> = match self.journal {
+            Some(_) => journal::commands(self, query),
+            None => vec![],
+        };
+        match *query {
+            Query::Enable(stn) => self.enable_station(stn),
+            Query::Disable(stn) => self.disable_station(stn),
+            Query::DisableStations(ref stns, ref incident) => {
+                self.disable_stations(stns.as_slice(), incident.as_ref().map(|i| i.as_slice()))
+            },
+            Query::EnableIncident(ref incident) => try!(self.enable_incident(incident.as_slice())),
+            Query::Alias(ref alias, stn) => try!(self.add_alias(alias.as_slice(), stn)),
+            Query::DisableSegment(a, b) => try!(self.disable_segment(a, b)),
+            Query::EnableSegment(a, b) => try!(self.enable_segment(a, b)),
+            Query::DisableBranch(ref branch) => try!(self.disable_branch(branch.as_slice())),
+            Query::EnableBranch(ref branch) => try!(self.enable_branch(branch.as_slice())),
+            Query::DisableLine(ref line) => try!(self.disable_line(line.as_slice())),
+            Query::EnableLine(ref line) => try!(self.enable_line(line.as_slice())),
+            Query::PassThrough(stn) => self.pass_through_station(stn),
+            Query::StopAt(stn) => self.stop_at_station(stn),
+            Query::Schedule(ref disruption) => try!(self.add_disruption(disruption.clone())),
+            Query::Unschedule(n) => { try!(self.remove_disruption(n)); },
+            Query::UnscheduleDisruption(ref disruption) => try!(self.cancel_disruption(disruption)),
+            Query::Route(..) | Query::RouteAt(..) | Query::ListSchedule | Query::Status => {
+                return Ok(());
+            },
+        }
+        if let Some(ref journal) = self.journal {
+            journal.record(self.clock.now(), commands.as_slice());
+        }
+        Ok(())
+    }
 
     /// Prints a list of stations and their ids.
     pub fn print_stations(&self) -> () {
         println!("StationID\tStation");
         for (sid, stn) in self.stations.iter().enumerate() {
+            if !self.is_station(sid) { continue; }
             println!("{}\t{}", sid, stn);
         }
     }
 }
 
+/// The one station in `candidates` found for `query`, which may name a station more
+/// than once if it was merged.
+fn single_station(query: &str, mut candidates: Vec<StationId>) -> Result<StationId, TQueryError> {
+    candidates.sort();
+    candidates.dedup();
+    if candidates.len() > 1 {
+        return Err(TQueryError::AmbiguousStation { query: query.to_string(),
+                                                   candidates: candidates });
+    }
+    Ok(candidates[0])
+}
+
 #[cfg(test)]
 mod subway_tests {
-    use super::Subway;
+    use super::{Subway, StationId, Connection, Coord};
+    use super::schedule::Closures;
+    use super::names::MatchKind;
+    use error::TQueryError;
 
     #[test]
     fn test_add_station() {
@@ -204,6 +785,22 @@ mod subway_tests {
         assert_eq!(a_id, dupe_id);
     }
 
+    #[test]
+    fn test_add_distinct_station() {
+        let mut subway = Subway::new();
+        let plain_id = subway.add_station("A");
+        let b_id = subway.add_distinct_station("A", "b");
+        let c_id = subway.add_distinct_station("A", "c");
+        assert!(plain_id != b_id && b_id != c_id);
+        assert_eq!(subway.add_distinct_station("A", "b"), b_id);
+        assert_eq!(subway.stations[b_id], "A (b)");
+        assert_eq!(subway.get_station_name(b_id).unwrap().as_slice(), "A");
+        assert_eq!(subway.get_station_name(c_id).unwrap().as_slice(), "A");
+
+        assert_eq!(subway.find_station("A").unwrap(), plain_id);
+        assert_eq!(subway.find_station("a (C)").unwrap(), c_id);
+    }
+
     #[test]
     fn test_add_connection() {
         let mut subway = Subway::new();
@@ -218,6 +815,63 @@ mod subway_tests {
         assert_eq!(c.to, b_id);
         assert_eq!(c.info.line.as_slice(), "foo");
         assert_eq!(c.info.branch.as_slice(), "bar");
+        assert_eq!(c.minutes, None);
+
+        let (_, timed_idx) = subway.add_timed_connection(b_id, a_id, "foo", "bar", Some(4));
+        let timed = &subway.connections[b_id][timed_idx];
+        assert_eq!(timed.cost, 4);
+        assert_eq!(timed.minutes, Some(4));
+
+        let (_, dupe_idx) = subway.add_connection(a_id, b_id, "foo", "bar");
+        assert_eq!(dupe_idx, conn_idx);
+    }
+
+    #[test]
+    fn test_add_transfer() {
+        let mut subway = Subway::new();
+        let a_id = subway.add_station("A");
+        let b_id = subway.add_station("B");
+        let b2_id = subway.add_station("B2");
+        let c_id = subway.add_station("C");
+        subway.add_connection(a_id, b_id, "x", "x");
+        subway.add_connection(b_id, a_id, "x", "x");
+        subway.add_connection(b2_id, c_id, "y", "y");
+        subway.add_connection(c_id, b2_id, "y", "y");
+        subway.set_location(b2_id, Coord::new(42.36, -71.06).unwrap());
+
+        let t_id = subway.add_transfer(b_id, b2_id, None, 2);
+        assert_eq!(t_id, b_id);
+        assert_eq!(subway.get_station_id("B2"), Some(&b_id));
+        assert!(subway.get_connection(b_id, c_id).is_some());
+        assert!(subway.get_connection(c_id, b_id).is_some());
+        assert!(subway.get_connection(c_id, b2_id).is_none());
+        assert_eq!(subway.transfers.get(&b_id).unwrap().walk, 2);
+        assert_eq!(subway.find_station("B").unwrap(), b_id);
+        assert_eq!(subway.get_location(b_id).map(|c| c.lat), Some(42.36));
+        assert!(Coord::new(91.0, 0.0).is_none());
+    }
+
+    #[test]
+    fn test_merged_station_state() {
+        let mut subway = Subway::new();
+        let a_id = subway.add_station("A");
+        let b_id = subway.add_station("B");
+        let b2_id = subway.add_station("B2");
+        subway.add_connection(a_id, b_id, "x", "x");
+        subway.add_connection(b2_id, a_id, "y", "y");
+        subway.disable_station(b2_id);
+        subway.pass_through_station(b2_id);
+        assert!(subway.disable_segment(a_id, b2_id).is_ok());
+
+        subway.add_transfer(b_id, b2_id, None, 0);
+        assert!(subway.is_station(b_id) && !subway.is_station(b2_id));
+        assert!(!subway.is_station(subway.size()));
+        assert!(subway.disabled_stations.contains(&b_id));
+        assert!(!subway.disabled_stations.contains(&b2_id));
+        assert!(!subway.get_connection(a_id, b_id).unwrap().active);
+        assert!(subway.pass_through_stations.contains(&b_id));
+        assert!(subway.closed_segments.contains(&(a_id, b_id)));
+        assert_eq!(subway.closed_segments.len(), 1);
     }
 
     #[test]
@@ -253,14 +907,182 @@ mod subway_tests {
         assert_eq!(a1_id, a1_res.unwrap());
 
         let multi_a = subway.find_station("A");
-        assert!(multi_a.is_err());
-        let multi_a_emsg = multi_a.unwrap_err();
-        assert!(multi_a_emsg.starts_with("disambiguate your destination"));
+        assert_eq!(multi_a, Err(TQueryError::AmbiguousStation { query: "A".to_string(),
+                                                                candidates: vec![a1_id, a2_id] }));
 
         let no_b = subway.find_station("B");
-        assert!(no_b.is_err());
-        let no_b_emsg = no_b.unwrap_err();
-        assert!(no_b_emsg.starts_with("No such station"));
+        assert_eq!(no_b, Err(TQueryError::NoSuchStation { query: "B".to_string(),
+                                                          suggestions: vec![] }));
+    }
+
+    #[test]
+    fn test_find_station_pattern() {
+        let mut subway = Subway::new();
+        let a1_id = subway.add_station("A1");
+        subway.add_station("A2");
+
+        assert_eq!(subway.find_station("/^A1$/").unwrap(), a1_id);
+        assert_eq!(subway.find_station("/A/").unwrap_err().code(), "ambiguous_station");
+        assert_eq!(subway.find_station("/^B/"),
+                   Err(TQueryError::NoSuchStation { query: "/^B/".to_string(),
+                                                    suggestions: vec![] }));
+        assert_eq!(subway.find_station("/(/").unwrap_err().code(), "invalid_pattern");
+    }
+
+    #[test]
+    fn test_find_station_hostile_input() {
+        let mut subway = Subway::new();
+        subway.add_station("Davis");
+        subway.add_station("Park Street");
+
+        // none of these is a pattern, and none names a station
+        let hostile = ["(", ")", "[", "\\", ".*", ".", "*", "+?", "^", "$", "|", "{1,}",
+                       "(?i)davis|park", "", "   ", "/", "\u{0}", "\u{202e}", "\u{1f687}"];
+        for input in hostile.iter() {
+            let found = subway.find_station(*input);
+            assert_eq!(found.unwrap_err().code(), "no_such_station");
+        }
+        let long: String = range(0, 10000).map(|_| "(").collect();
+        assert_eq!(subway.find_station(long.as_slice()).unwrap_err().code(), "no_such_station");
+
+        // broken patterns are reported rather than panicking
+        for input in ["/(/", "/[/", "/\\/", "/*/", "/(?P<x/", "/a{2,1}/"].iter() {
+            assert_eq!(subway.find_station(*input).unwrap_err().code(), "invalid_pattern");
+        }
+        let pattern = format!("/{}/", long);
+        assert_eq!(subway.find_station(pattern.as_slice()).unwrap_err().code(),
+                   "invalid_pattern");
+        assert_eq!(subway.find_station("//").unwrap_err().code(), "ambiguous_station");
+    }
+
+    #[test]
+    fn test_aliases() {
+        let mut subway = Subway::new();
+        let dtx_id = subway.add_station("Downtown Crossing Station");
+        let harvard_id = subway.add_station("Harvard Square Station");
+        let harvard_ave_id = subway.add_station("Harvard Avenue Station");
+        let kendall_id = subway.add_station("Kendall Station");
+        let kendall2_id = subway.add_station("Kendall");
+
+        assert!(subway.find_station("DTX").is_err());
+        assert_eq!(subway.find_station("Harvard").unwrap_err().describe(&subway).as_slice(),
+                   "disambiguate your destination:\n1. Harvard Square Station\n\
+                    2. Harvard Avenue Station\nreply `choose N` to pick one");
+        subway.add_alias("DTX", dtx_id).unwrap();
+        subway.add_alias("Harvard", harvard_id).unwrap();
+        subway.add_alias("Kendall/MIT", kendall2_id).unwrap();
+        assert!(subway.add_alias("dtx", dtx_id).is_ok());
+        let taken = subway.add_alias("harvard", harvard_ave_id).unwrap_err();
+        assert_eq!(taken.describe(&subway).as_slice(),
+                   "Alias taken: harvard is already Harvard Square Station");
+        assert_eq!(subway.find_station("dtx").unwrap(), dtx_id);
+        assert_eq!(subway.find_station("Harvard").unwrap(), harvard_id);
+        assert_eq!(subway.find_station("Harvard Ave").unwrap(), harvard_ave_id);
+        assert_eq!(subway.find_station("kendall mit").unwrap(), kendall2_id);
+
+        // aliases follow their station when it is merged into another
+        subway.add_transfer(kendall_id, kendall2_id, None, 0);
+        assert_eq!(subway.get_alias("Kendall/MIT"), Some(&kendall_id));
+    }
+
+    #[test]
+    fn test_find_station_fuzzy() {
+        let mut subway = Subway::new();
+        let harvard_id = subway.add_station("Harvard Square Station");
+        let park_id = subway.add_station("Park Street");
+        let kenmore_id = subway.add_station("Kenmore");
+        subway.add_station("Kendall/MIT");
+
+        assert_eq!(subway.find_station("HARVARD square").unwrap(), harvard_id);
+        assert_eq!(subway.find_station("harvard").unwrap(), harvard_id);
+        assert_eq!(subway.find_station("street").unwrap(), park_id);
+        assert_eq!(subway.find_station("ken").unwrap_err().code(), "ambiguous_station");
+
+        let typo = subway.find_station("harvrd");
+        assert_eq!(typo, Err(TQueryError::NoSuchStation { query: "harvrd".to_string(),
+                                                          suggestions: vec![harvard_id] }));
+        assert_eq!(typo.unwrap_err().describe(&subway).as_slice(),
+                   "No such station: harvrd, did you mean Harvard Square Station?");
+
+        let found = subway.search_stations("kenmre");
+        assert_eq!(found[0], (kenmore_id, MatchKind::Fuzzy(1)));
+        assert!(subway.find_station("kenmre").is_err());
+    }
+
+    #[test]
+    fn test_closures() {
+        let open = |&: subway: &Subway, from: StationId, connection: &Connection| {
+            Closures::at(subway, subway.clock.now()).is_open(from, connection)
+        };
+        let mut subway = Subway::new();
+        let a_id = subway.add_station("A");
+        let b_id = subway.add_station("B");
+        let c_id = subway.add_station("C");
+        subway.add_connection(a_id, b_id, "red", "Mattapan");
+        subway.add_connection(b_id, c_id, "red", "red");
+
+        assert!(subway.disable_segment(b_id, a_id).is_ok());
+        assert!(subway.closed_segments.contains(&(a_id, b_id)));
+        assert!(!open(&subway, a_id, subway.get_connection(a_id, b_id).unwrap()));
+        assert!(open(&subway, b_id, subway.get_connection(b_id, c_id).unwrap()));
+        assert_eq!(subway.disable_segment(a_id, c_id).unwrap_err().code(), "no_such_segment");
+        assert!(subway.enable_segment(a_id, b_id).is_ok());
+        assert!(subway.closed_segments.is_empty());
+
+        assert!(subway.disable_branch("MATTAPAN").is_ok());
+        assert!(!open(&subway, a_id, subway.get_connection(a_id, b_id).unwrap()));
+        assert!(open(&subway, b_id, subway.get_connection(b_id, c_id).unwrap()));
+        assert_eq!(subway.disable_branch("Ashmont").unwrap_err().code(), "no_such_branch");
+        assert!(subway.enable_branch("mattapan").is_ok());
+        assert!(subway.closed_branches.is_empty());
+
+        assert!(subway.disable_line("Red").is_ok());
+        assert!(subway.closed_lines.contains("red"));
+        assert!(!open(&subway, b_id, subway.get_connection(b_id, c_id).unwrap()));
+        assert_eq!(subway.disable_line("blue").unwrap_err().code(), "no_such_line");
+        assert!(subway.enable_line("red").is_ok());
+        assert!(subway.closed_lines.is_empty());
+
+        // pass-through stations keep their connections
+        subway.pass_through_station(b_id);
+        assert!(subway.pass_through_stations.contains(&b_id));
+        assert!(open(&subway, b_id, subway.get_connection(b_id, c_id).unwrap()));
+        subway.stop_at_station(b_id);
+        assert!(subway.pass_through_stations.is_empty());
+    }
+
+    #[test]
+    fn test_incidents() {
+        let mut subway = Subway::new();
+        let a_id = subway.add_station("A");
+        let b_id = subway.add_station("B");
+        let c_id = subway.add_station("C");
+        subway.add_connection(a_id, b_id, "x", "x");
+        subway.add_connection(b_id, c_id, "x", "x");
+
+        subway.disable_station(c_id);
+        subway.disable_stations(&[a_id, b_id, c_id], Some("42"));
+        assert_eq!(subway.incidents.get(&"42".to_string()), Some(&vec![a_id, b_id]));
+        assert!(!subway.get_connection(a_id, b_id).unwrap().active);
+        subway.disable_stations(&[b_id], Some("43"));
+        assert_eq!(subway.incidents_holding(b_id), vec!["42".to_string(), "43".to_string()]);
+
+        assert!(subway.enable_incident("42").is_ok());
+        assert_eq!(subway.disabled_stations.len(), 2);
+        assert!(subway.disabled_stations.contains(&b_id));
+        assert!(!subway.get_connection(a_id, b_id).unwrap().active);
+        assert!(subway.enable_incident("43").is_ok());
+        assert_eq!(subway.disabled_stations.len(), 1);
+        assert!(subway.disabled_stations.contains(&c_id));
+        assert_eq!(subway.enable_incident("43").unwrap_err().code(), "no_such_incident");
+
+        // reopening by hand releases the station from its incident
+        subway.disable_stations(&[a_id], Some("44"));
+        subway.enable_station(a_id);
+        assert!(subway.incidents_holding(a_id).is_empty());
+        subway.disable_station(a_id);
+        assert!(subway.enable_incident("44").is_ok());
+        assert!(subway.disabled_stations.contains(&a_id));
     }
 
     #[test]
@@ -296,6 +1118,17 @@ mod subway_tests {
         assert!(no_c.is_none());
     }
 
+    #[test]
+    fn test_lines() {
+        let mut subway = Subway::new();
+        let a_id = subway.add_station("A");
+        let b_id = subway.add_station("B");
+        subway.add_connection(a_id, b_id, "y", "y");
+        subway.add_connection(b_id, a_id, "x", "x1");
+        subway.add_connection(a_id, b_id, "x", "x2");
+        assert_eq!(subway.lines(), vec!["x".to_string(), "y".to_string()]);
+    }
+
     #[test]
     fn test_size() {
         let mut subway = Subway::new();
@@ -327,6 +1160,7 @@ mod subway_tests {
 
             let c_b = subway.get_connection(c_id, b_id).unwrap();
             assert_eq!(c_b.active, true);
+            assert!(subway.disabled_stations.contains(&a_id));
         }
 
         subway.enable_station(a_id);
@@ -335,6 +1169,7 @@ mod subway_tests {
             assert_eq!(a_b.active, true);
             let b_a = subway.get_connection(b_id, a_id).unwrap();
             assert_eq!(b_a.active, true);
+            assert!(!subway.disabled_stations.contains(&a_id));
         }
 
     }
@@ -0,0 +1,97 @@
+//! Helpers for comparing station names as riders and data maintainers write them.
+#![allow(unstable)]
+
+use std::cmp;
+
+/// Reduces a station name to a canonical form for comparison: lower case, punctuation
+/// dropped, whitespace collapsed and any trailing "Station" removed.
+pub fn normalize(name: &str) -> String {
+    let cleaned: String = name.chars()
+                              .map(|c| if c.is_alphanumeric() { c.to_lowercase() } else { ' ' })
+                              .collect();
+    let mut words: Vec<&str> = cleaned.split(' ').filter(|w| !w.is_empty()).collect();
+    if words.len() > 1 && words.last() == Some(&"station") { words.pop(); }
+    words.connect(" ")
+}
+
+/// How well a station name matches what a rider typed, best first.
+#[derive(Copy, Eq, PartialEq, Ord, PartialOrd, Show)]
+pub enum MatchKind {
+    /// The names are the same once normalized
+    Exact,
+    /// The name starts with what was typed
+    Prefix,
+    /// The name contains what was typed
+    Substring,
+    /// What was typed is this many edits away from the name, or from as many of its
+    /// leading words as were typed
+    Fuzzy(usize),
+}
+
+/// The number of single character insertions, deletions and substitutions needed to
+/// turn `a` into `b`.
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = range(0, b.len() + 1).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal: usize = row[0];
+        row[0] = i + 1;
+        for j in range(0, b.len()) {
+            let substitute: usize = diagonal + if ca == b[j] { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = *[substitute, row[j] + 1, row[j + 1] + 1].iter().min().unwrap();
+        }
+    }
+    row[b.len()]
+}
+
+/// Compares what a rider typed against a station name, both normalized. Typos are
+/// tolerated up to one edit for every three characters typed.
+pub fn match_name(query: &str, name: &str) -> Option<MatchKind> {
+    if query.is_empty() { return None; }
+    if name == query { return Some(MatchKind::Exact); }
+    if name.starts_with(query) { return Some(MatchKind::Prefix); }
+    if name.contains(query) { return Some(MatchKind::Substring); }
+
+    let typed: usize = query.split(' ').count();
+    let leading: Vec<&str> = name.split(' ').take(typed).collect();
+    let distance: usize = cmp::min(edit_distance(query, name),
+                                   edit_distance(query, leading.connect(" ").as_slice()));
+    if distance <= query.chars().count() / 3 { Some(MatchKind::Fuzzy(distance)) } else { None }
+}
+
+#[cfg(test)]
+mod names_tests {
+    use super::{normalize, edit_distance, match_name};
+    use super::MatchKind::{Exact, Prefix, Substring, Fuzzy};
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("Haymarket Station").as_slice(), "haymarket");
+        assert_eq!(normalize("  haymarket ").as_slice(), "haymarket");
+        assert_eq!(normalize("Charles/MGH Station").as_slice(), "charles mgh");
+        assert_eq!(normalize("St. Paul Street").as_slice(), "st paul street");
+        assert_eq!(normalize("Station").as_slice(), "station");
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("harvard", "harvard"), 0);
+        assert_eq!(edit_distance("harvrd", "harvard"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn test_match_name() {
+        assert_eq!(match_name("harvard", "harvard"), Some(Exact));
+        assert_eq!(match_name("harvard", "harvard square"), Some(Prefix));
+        assert_eq!(match_name("square", "harvard square"), Some(Substring));
+        assert_eq!(match_name("harvrd", "harvard square"), Some(Fuzzy(1)));
+        assert_eq!(match_name("hravrd sqare", "harvard square"), Some(Fuzzy(4)));
+        assert_eq!(match_name("kendall", "harvard square"), None);
+        assert_eq!(match_name("d", "a"), None);
+        assert_eq!(match_name("", "a"), None);
+        assert!(Prefix < Substring && Substring < Fuzzy(0));
+    }
+}
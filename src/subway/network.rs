@@ -0,0 +1,291 @@
+//! Describes a whole `Subway` as JSON or TOML, for tooling that would rather not write
+//! `.dat` files, and exports a `Subway` back out the same way.
+//!
+//! The description lists every station and every connection. Stations are referred to
+//! by their position in the `stations` list:
+//!
+//! ```json
+//! {
+//!   "metadata": { "agency": "MBTA" },
+//!   "stations": [
+//!     { "label": "Kenmore Station", "name": "Kenmore Station", "disabled": false,
+//!       "lat": 42.348949, "lon": -71.095169 },
+//!     { "label": "St. Paul Street (B)", "name": "St. Paul Street", "disabled": false },
+//!     { "label": "Haymarket", "name": "Haymarket", "disabled": false, "merged_into": 2 }
+//!   ],
+//!   "connections": [
+//!     { "from": 0, "to": 1, "line": "green", "branch": "B", "cost": 1, "active": true,
+//!       "minutes": 4 }
+//!   ],
+//!   "transfers": [ { "station": 2, "cost": 3, "walk": 2 } ],
+//!   "aliases": { "kenmore square": 0 }
+//! }
+//! ```
+//!
+//! * `label` tells a station apart from all others, `name` is what riders see
+//! * `lat` and `lon` locate a station and may be left out
+//! * `merged_into` marks a name another station is also known by, see
+//!   `Subway::add_transfer`
+//! * connections run one way, so a two way segment is listed twice; `minutes` and the
+//!   transfer `cost` may be left out
+//!
+//! * `aliases` maps other names riders use onto stations, see `Subway::add_alias`
+//!
+//! Only the network is described, with its disabled stations. Closed segments, branches
+//! and lines, pass-through stations, the disabled policy, scheduled disruptions and
+//! incidents are not; they are kept by the journal and the schedule file instead.
+//!
+//! The TOML form has the same fields, with `[[stations]]`, `[[connections]]` and
+//! `[[transfers]]` tables.
+#![allow(unstable)]
+
+use std::collections::BTreeMap;
+
+use rustc_serialize::json;
+use toml;
+
+use error::TQueryError;
+
+use super::{Subway, StationId, Transfer, Coord};
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Clone, Show)]
+pub struct Network {
+    pub metadata: BTreeMap<String, String>,
+    pub stations: Vec<StationDescription>,
+    pub connections: Vec<ConnectionDescription>,
+    pub transfers: Vec<TransferDescription>,
+    pub aliases: BTreeMap<String, usize>,
+}
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Clone, Show)]
+pub struct StationDescription {
+    pub label: String,
+    pub name: String,
+    pub disabled: bool,
+    pub merged_into: Option<usize>,
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+}
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Clone, Show)]
+pub struct ConnectionDescription {
+    pub from: usize,
+    pub to: usize,
+    pub line: String,
+    pub branch: String,
+    pub cost: usize,
+    pub active: bool,
+    pub minutes: Option<usize>,
+}
+
+#[derive(RustcEncodable, RustcDecodable, PartialEq, Clone, Show)]
+pub struct TransferDescription {
+    pub station: usize,
+    pub cost: Option<usize>,
+    pub walk: usize,
+}
+
+/// Describes `subway` in full.
+pub fn describe_network(subway: &Subway) -> Network {
+    let stations = subway.stations.iter().enumerate().map(|(id, label)| {
+        let canonical: StationId = *subway.get_station_id(label.as_slice()).unwrap();
+        let location: Option<&Coord> = subway.get_location(id);
+        StationDescription {
+            label: label.clone(),
+            name: subway.names[id].clone(),
+            disabled: subway.disabled_stations.contains(&id),
+            merged_into: if canonical != id { Some(canonical) } else { None },
+            lat: location.map(|c| c.lat),
+            lon: location.map(|c| c.lon),
+        }
+    }).collect();
+
+    let mut connections: Vec<ConnectionDescription> = vec![];
+    for (from, cs) in subway.connections.iter().enumerate() {
+        for c in cs.iter() {
+            connections.push(ConnectionDescription {
+                from: from,
+                to: c.to,
+                line: c.info.line.clone(),
+                branch: c.info.branch.clone(),
+                cost: c.cost,
+                active: c.active,
+                minutes: c.minutes,
+            });
+        }
+    }
+
+    let mut transfers: Vec<TransferDescription> = subway.transfers.iter().map(|(id, t)| {
+        TransferDescription { station: *id, cost: t.cost, walk: t.walk }
+    }).collect();
+    transfers.sort_by(|a, b| a.station.cmp(&b.station));
+
+    Network {
+        metadata: subway.metadata.clone(),
+        stations: stations,
+        connections: connections,
+        transfers: transfers,
+        aliases: subway.aliases.iter().map(|(alias, id)| (alias.clone(), *id)).collect(),
+    }
+}
+
+/// Adds everything in `network` to `subway`. Stations are matched to those already in
+/// `subway` by label. `file` names the source of `network` in errors.
+pub fn load_network(subway: &mut Subway, network: &Network, file: &str)
+                    -> Result<(), TQueryError> {
+    let count: usize = network.stations.len();
+    let check = |&: id: usize, what: &str| -> Result<(), TQueryError> {
+        if id < count { return Ok(()); }
+        Err(TQueryError::DataFormat {
+            file: file.to_string(),
+            line: 0,
+            reason: format!("{} refers to station {}, but there are only {}", what, id, count),
+        })
+    };
+    for stn in network.stations.iter() {
+        if let Some(id) = stn.merged_into { try!(check(id, "merged_into")); }
+    }
+    for c in network.connections.iter() {
+        try!(check(c.from, "connection"));
+        try!(check(c.to, "connection"));
+    }
+    for t in network.transfers.iter() { try!(check(t.station, "transfer")); }
+    for id in network.aliases.values() { try!(check(*id, "alias")); }
+    let mut locations: Vec<Option<Coord>> = vec![];
+    for stn in network.stations.iter() {
+        let location = match (stn.lat, stn.lon) {
+            (Some(lat), Some(lon)) => match Coord::new(lat, lon) {
+                Some(coord) => Some(coord),
+                None => return Err(TQueryError::DataFormat {
+                    file: file.to_string(),
+                    line: 0,
+                    reason: format!("station `{}` is at an invalid location", stn.label),
+                }),
+            },
+            _ => None,
+        };
+        locations.push(location);
+    }
+
+    let ids: Vec<StationId> = network.stations.iter().map(|stn| {
+        subway.add_labelled_station(stn.label.clone(), stn.name.as_slice())
+    }).collect();
+    for (i, stn) in network.stations.iter().enumerate() {
+        if let Some(canonical) = stn.merged_into {
+            subway.station_name_id_map.insert(stn.label.clone(), ids[canonical]);
+        }
+        if stn.disabled { subway.disabled_stations.insert(ids[i]); }
+        if let Some(coord) = locations[i] { subway.set_location(ids[i], coord); }
+    }
+
+    for c in network.connections.iter() {
+        let (from, idx) = subway.add_timed_connection(ids[c.from], ids[c.to],
+                                                      c.line.as_slice(), c.branch.as_slice(),
+                                                      c.minutes);
+        let connection = &mut subway.connections[from][idx];
+        connection.cost = c.cost;
+        connection.active = c.active;
+    }
+    for t in network.transfers.iter() {
+        subway.transfers.insert(ids[t.station], Transfer { cost: t.cost, walk: t.walk });
+    }
+    for (alias, id) in network.aliases.iter() {
+        try!(subway.add_alias(alias.as_slice(), ids[*id]));
+    }
+    for (k, v) in network.metadata.iter() {
+        subway.metadata.insert(k.clone(), v.clone());
+    }
+    Ok(())
+}
+
+/// Loads a JSON network description into `subway`.
+pub fn load_json(subway: &mut Subway, content: &str, file: &str) -> Result<(), TQueryError> {
+    match json::decode::<Network>(content) {
+        Ok(network) => load_network(subway, &network, file),
+        Err(e) => Err(TQueryError::DataFormat {
+            file: file.to_string(),
+            line: 0,
+            reason: format!("invalid JSON network description: {:?}", e),
+        }),
+    }
+}
+
+/// Loads a TOML network description into `subway`.
+pub fn load_toml(subway: &mut Subway, content: &str, file: &str) -> Result<(), TQueryError> {
+    match toml::decode_str::<Network>(content) {
+        Some(network) => load_network(subway, &network, file),
+        None => Err(TQueryError::DataFormat {
+            file: file.to_string(),
+            line: 0,
+            reason: "invalid TOML network description".to_string(),
+        }),
+    }
+}
+
+/// Describes `subway` as JSON.
+pub fn export_json(subway: &Subway) -> String {
+    json::encode(&describe_network(subway))
+}
+
+/// Describes `subway` as TOML.
+pub fn export_toml(subway: &Subway) -> String {
+    toml::encode_str(&describe_network(subway))
+}
+
+#[cfg(test)]
+mod network_tests {
+    use super::{export_json, export_toml, load_json, load_toml};
+    use super::super::{Subway, Coord};
+
+    fn sample() -> Subway {
+        let mut subway = Subway::new();
+        let a_id = subway.add_station("A");
+        let b_id = subway.add_distinct_station("B", "x");
+        let c_id = subway.add_station("C");
+        let c2_id = subway.add_station("C Station");
+        let d_id = subway.add_station("D");
+        subway.add_timed_connection(a_id, b_id, "x", "x", Some(3));
+        subway.add_timed_connection(b_id, a_id, "x", "x", Some(3));
+        subway.add_connection(b_id, c_id, "x", "x1");
+        subway.add_connection(c_id, b_id, "x", "x1");
+        subway.add_connection(c2_id, d_id, "y", "y");
+        subway.add_connection(d_id, c2_id, "y", "y");
+        subway.add_transfer(c_id, c2_id, Some(4), 1);
+        subway.disable_station(a_id);
+        subway.metadata.insert("agency".to_string(), "test".to_string());
+        subway.set_location(b_id, Coord::new(42.5, -71.25).unwrap());
+        subway.add_alias("Bee", b_id).unwrap();
+        subway
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let mut subway = sample();
+        // closures are left to the journal
+        assert!(subway.disable_segment(0, 1).is_ok());
+        subway.pass_through_station(1);
+        let mut loaded = Subway::new();
+        load_json(&mut loaded, export_json(&subway).as_slice(), "test.json").unwrap();
+        assert!(loaded == subway);
+        assert_eq!(loaded.find_station("C Station").unwrap(), loaded.find_station("C").unwrap());
+    }
+
+    #[test]
+    fn test_toml_round_trip() {
+        let subway = sample();
+        let mut loaded = Subway::new();
+        load_toml(&mut loaded, export_toml(&subway).as_slice(), "test.toml").unwrap();
+        assert!(loaded == subway);
+    }
+
+    #[test]
+    fn test_load_json_checks_station_ids() {
+        let mut subway = Subway::new();
+        let json = r#"{"metadata": {}, "stations": [], "transfers": [], "aliases": {},
+                       "connections": [{"from": 0, "to": 1, "line": "x", "branch": "x",
+                                        "cost": 1, "active": true}]}"#;
+        let loaded = load_json(&mut subway, json, "bad.json");
+        assert_eq!(loaded.unwrap_err().code(), "data_format");
+        assert!(load_json(&mut subway, "{", "bad.json").is_err());
+    }
+}
@@ -9,64 +9,243 @@ use std::uint;
 use std::collections::BinaryHeap;
 use std::collections::HashMap;
 use std::cmp::Ordering;
+use std::str::FromStr;
 
-use super::{Subway, StationId, StationInfo};
+use time::Timespec;
 
-const DISABLE_COST: usize = 100;
+use error::TQueryError;
 
-// so rustc shushes
-#[derive(Copy)]
+use super::{Subway, StationId, StationInfo, Connection, WALK_LINE};
+use super::schedule::{Closures, Disruption, parse_time, parse_closure};
+#[cfg(test)]
+use super::schedule::{Closure, FixedClock};
+
+/// Extra cost of going through a disabled station when penalized, unless configured.
+pub const DISABLE_COST: usize = 100;
+
+/// How routes treat disabled stations.
+#[derive(Copy, Eq, PartialEq, Clone, Show)]
+pub enum DisabledPolicy {
+    /// Routes never go through a disabled station
+    Forbid,
+    /// Routes may go through a disabled station, at the given extra cost
+    Penalize(usize),
+    /// Trains run through disabled stations without stopping
+    PassThrough,
+}
+
+impl FromStr for DisabledPolicy {
+    /// Parses `forbid`, `penalize`, `penalize:COST` or `pass-through`.
+    fn from_str(s: &str) -> Option<DisabledPolicy> {
+        match s {
+            "forbid" => Some(DisabledPolicy::Forbid),
+            "penalize" => Some(DisabledPolicy::Penalize(DISABLE_COST)),
+            "pass-through" => Some(DisabledPolicy::PassThrough),
+            _ if s.starts_with("penalize:") => {
+                s.slice_from("penalize:".len()).parse::<usize>().map(DisabledPolicy::Penalize)
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Looks up the stations listed in `list`, separated by commas, with `find`. Station
+/// names may contain commas themselves, so if any part of the list names no station,
+/// the whole list is looked up as the one station instead.
+fn find_stations<F>(list: &str, find: &F) -> Result<Vec<StationId>, TQueryError>
+                    where F: Fn(&str) -> Result<StationId, TQueryError> {
+    let mut stn_ids: Vec<StationId> = vec![];
+    for name in list.split(',') {
+        match (*find)(name.trim()) {
+            Ok(stn_id) => if !stn_ids.contains(&stn_id) { stn_ids.push(stn_id); },
+            Err(e) => return (*find)(list.trim()).map(|stn_id| vec![stn_id]).map_err(|_| e),
+        }
+    }
+    Ok(stn_ids)
+}
+
+#[derive(Clone, Show)]
 pub enum Query {
     Route(StationId, StationId),
+    /// A route for a rider setting off at the given time
+    RouteAt(StationId, StationId, Timespec),
     Enable(StationId),
     Disable(StationId),
+    /// Disables several stations at once, for the named incident if given
+    DisableStations(Vec<StationId>, Option<String>),
+    /// Reopens the stations the named incident holds disabled
+    EnableIncident(String),
+    /// Lets a station be found by another name from now on
+    Alias(String, StationId),
+    /// Closes the segment between two adjacent stations, in both directions
+    DisableSegment(StationId, StationId),
+    EnableSegment(StationId, StationId),
+    /// Suspends the branch with the given name, on whichever line it is
+    DisableBranch(String),
+    EnableBranch(String),
+    DisableLine(String),
+    EnableLine(String),
+    /// Has trains run through a station without stopping
+    PassThrough(StationId),
+    /// Has trains stop at a station again
+    StopAt(StationId),
+    /// Plans a disruption ahead of time
+    Schedule(Disruption),
+    /// Calls off the planned disruption with the given number, counting from 1
+    Unschedule(usize),
+    /// Calls off the planned disruption the same as the one given
+    UnscheduleDisruption(Disruption),
+    /// Lists the planned disruptions
+    ListSchedule,
+    /// Lists the disabled stations and when each was disabled
+    Status,
 }
 
 impl Query {
-    pub fn new(subway: &Subway, line: &str) -> Result<Query, String> {
+    pub fn new(subway: &Subway, line: &str) -> Result<Query, TQueryError> {
+        Query::parse(line, |&: stn: &str| subway.find_station(stn))
+    }
+
+    /// Like `new`, but stations are looked up with `find` rather than
+    /// `Subway::find_station`.
+    pub fn parse<F>(line: &str, find: F) -> Result<Query, TQueryError>
+                    where F: Fn(&str) -> Result<StationId, TQueryError> {
+        let route_at_re: regex::Regex =
+            regex!(r"^from (?P<from>.+) to (?P<to>.+) depart at (?P<at>.+)$");
         let route_re: regex::Regex = regex!(r"^from (?P<from>.+) to (?P<to>.+)$");
         let disable_re: regex::Regex = regex!(r"^disable (?P<station>.+)$");
         let enable_re: regex::Regex  = regex!(r"^enable (?P<station>.+)$");
+        let alias_re: regex::Regex   = regex!(r"^alias (?P<alias>[^=]+)=(?P<station>.+)$");
+        let segment_re: regex::Regex =
+            regex!(r"^(?P<action>disable|enable) segment (?P<from>.+) to (?P<to>.+)$");
+        let service_re: regex::Regex =
+            regex!(r"^(?P<action>disable|enable) (?P<kind>branch|line) (?P<name>.+)$");
+        let pass_re: regex::Regex    = regex!(r"^pass through (?P<station>.+)$");
+        let stop_re: regex::Regex    = regex!(r"^stop at (?P<station>.+)$");
+        let schedule_re: regex::Regex =
+            regex!(r"^schedule (?P<target>.+) from (?P<start>.+) until (?P<end>.+)$");
+        let unschedule_re: regex::Regex = regex!(r"^unschedule (?P<n>\d+)$");
+        let cancel_re: regex::Regex =
+            regex!(r"^unschedule (?P<target>.+) from (?P<start>.+) until (?P<end>.+)$");
+        let disable_many_re: regex::Regex =
+            regex!(r"^disable (?P<stations>.+?)(?: for incident (?P<incident>.+))?$");
+        let incident_re: regex::Regex = regex!(r"^enable incident (?P<incident>.+)$");
+
+        if line == "schedule" { return Ok(Query::ListSchedule); }
+        if line == "status" { return Ok(Query::Status); }
+
+        if schedule_re.is_match(line) {
+            let caps = schedule_re.captures(line).unwrap();
+            let closure = try!(parse_closure(caps.name("target").unwrap(), &find));
+            let start = try!(parse_time(caps.name("start").unwrap()));
+            let end = try!(parse_time(caps.name("end").unwrap()));
+            return Ok(Query::Schedule(Disruption { closure: closure, start: start, end: end }));
+        }
+
+        if unschedule_re.is_match(line) {
+            let caps = unschedule_re.captures(line).unwrap();
+            return match caps.name("n").unwrap().parse::<usize>() {
+                Some(n) => Ok(Query::Unschedule(n)),
+                None => Err(TQueryError::ParseQuery { query: line.to_string() }),
+            };
+        }
+
+        if cancel_re.is_match(line) {
+            let caps = cancel_re.captures(line).unwrap();
+            let closure = try!(parse_closure(caps.name("target").unwrap(), &find));
+            let start = try!(parse_time(caps.name("start").unwrap()));
+            let end = try!(parse_time(caps.name("end").unwrap()));
+            let disruption = Disruption { closure: closure, start: start, end: end };
+            return Ok(Query::UnscheduleDisruption(disruption));
+        }
+
+        if route_at_re.is_match(line) {
+            let caps = route_at_re.captures(line).unwrap();
+            let from_id = try!(find(caps.name("from").unwrap()));
+            let to_id = try!(find(caps.name("to").unwrap()));
+            let at = try!(parse_time(caps.name("at").unwrap()));
+            return Ok(Query::RouteAt(from_id, to_id, at));
+        }
 
         if route_re.is_match(line) {
             let caps = route_re.captures(line).unwrap();
             let to = caps.name("to").unwrap();
             let from = caps.name("from").unwrap();
-            match subway.find_station(from) {
-                Ok(from_id) => {
-                    match subway.find_station(to) {
-                        Ok(to_id) => { return Ok(Query::Route(from_id, to_id)); },
-                        Err(e)    => { return Err(e); }
-                    }
-                },
-                Err(e) => { return Err(e); },
-            }
+            let from_id = try!(find(from));
+            let to_id = try!(find(to));
+            return Ok(Query::Route(from_id, to_id));
+        }
+
+        if segment_re.is_match(line) {
+            let caps = segment_re.captures(line).unwrap();
+            let from_id = try!(find(caps.name("from").unwrap()));
+            let to_id = try!(find(caps.name("to").unwrap()));
+            return Ok(match caps.name("action").unwrap() {
+                "disable" => Query::DisableSegment(from_id, to_id),
+                _ => Query::EnableSegment(from_id, to_id),
+            });
+        }
+
+        if service_re.is_match(line) {
+            let caps = service_re.captures(line).unwrap();
+            let name: String = caps.name("name").unwrap().trim().to_string();
+            return Ok(match (caps.name("action").unwrap(), caps.name("kind").unwrap()) {
+                ("disable", "branch") => Query::DisableBranch(name),
+                ("disable", _) => Query::DisableLine(name),
+                (_, "branch") => Query::EnableBranch(name),
+                _ => Query::EnableLine(name),
+            });
+        }
+
+        if pass_re.is_match(line) {
+            let caps = pass_re.captures(line).unwrap();
+            return find(caps.name("station").unwrap()).map(|sid| Query::PassThrough(sid));
+        }
+
+        if stop_re.is_match(line) {
+            let caps = stop_re.captures(line).unwrap();
+            return find(caps.name("station").unwrap()).map(|sid| Query::StopAt(sid));
+        }
+
+        if incident_re.is_match(line) {
+            let caps = incident_re.captures(line).unwrap();
+            return Ok(Query::EnableIncident(caps.name("incident").unwrap().trim().to_string()));
+        }
+
+        if disable_many_re.is_match(line) &&
+                (line.contains(",") || line.contains(" for incident ")) {
+            let caps = disable_many_re.captures(line).unwrap();
+            let stn_ids: Vec<StationId> = try!(find_stations(caps.name("stations").unwrap(),
+                                                             &find));
+            let incident = caps.name("incident").map(|i| i.trim().to_string());
+            if stn_ids.len() == 1 && incident.is_none() { return Ok(Query::Disable(stn_ids[0])); }
+            return Ok(Query::DisableStations(stn_ids, incident));
         }
 
         if disable_re.is_match(line) {
             let caps = disable_re.captures(line).unwrap();
             let stn = caps.name("station").unwrap();
-            match subway.find_station(stn) {
-                Ok(sid) => { return Ok(Query::Disable(sid)); },
-                Err(e)  => { return Err(e); },
-            }
+            return find(stn).map(|sid| Query::Disable(sid));
         }
 
         if enable_re.is_match(line) {
             let caps = enable_re.captures(line).unwrap();
             let stn = caps.name("station").unwrap();
-            match subway.find_station(stn) {
-                Ok(sid) => { return Ok(Query::Enable(sid)); },
-                Err(e)  => { return Err(e); },
-            }
+            return find(stn).map(|sid| Query::Enable(sid));
         }
-        let emsg = format!("unable to parse query: {}", line);
-        Err(emsg)
+
+        if alias_re.is_match(line) {
+            let caps = alias_re.captures(line).unwrap();
+            let alias = caps.name("alias").unwrap().trim();
+            let stn = caps.name("station").unwrap().trim();
+            return find(stn).map(|sid| Query::Alias(alias.to_string(), sid));
+        }
+        Err(TQueryError::ParseQuery { query: line.to_string() })
     }
 
     pub fn is_route(&self) -> bool {
         match *self {
-            Query::Route(_, _) => true,
+            Query::Route(_, _) | Query::RouteAt(_, _, _) => true,
             _ => false,
         }
     }
@@ -84,6 +263,21 @@ impl Query {
             _ => false,
         }
     }
+
+    pub fn is_alias(&self) -> bool {
+        match *self {
+            Query::Alias(_, _) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the query changes the subway rather than just asking about it.
+    pub fn is_mutation(&self) -> bool {
+        match *self {
+            Query::ListSchedule | Query::Status => false,
+            _ => !self.is_route(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -91,6 +285,8 @@ mod query_tests {
     use super::Query;
     use super::Query::{Route, Enable, Disable};
     use super::super::{Subway, StationId, StationInfo};
+    use super::super::schedule::{Closure, parse_time};
+    use error::TQueryError;
 
     #[test]
     fn test_new() {
@@ -108,8 +304,13 @@ mod query_tests {
 
         let no_route = Query::new(&subway, "from A to D");
         assert!(no_route.is_err());
-        let no_route_msg = no_route.err().unwrap();
-        assert_eq!(no_route_msg.as_slice(), "No such station: D");
+        let no_route_err = no_route.err().unwrap();
+        assert_eq!(no_route_err, TQueryError::NoSuchStation { query: "D".to_string(),
+                                                             suggestions: vec![] });
+        assert_eq!(format!("{}", no_route_err).as_slice(), "No such station: D");
+
+        let gibberish = Query::new(&subway, "take me to A");
+        assert_eq!(gibberish.err().unwrap().code(), "parse_query");
 
         let dis_a = Query::new(&subway, "disable A");
         assert!(dis_a.is_ok());
@@ -120,72 +321,355 @@ mod query_tests {
         assert!(en_a.is_ok());
         let enable_a = en_a.unwrap();
         assert!(enable_a.is_enable());
+
+        // station names are matched regardless of case
+        assert!(Query::new(&subway, "from a to b").unwrap().is_route());
+        // and are not patterns unless asked to be
+        assert_eq!(Query::new(&subway, "from ( to B").err().unwrap().code(), "no_such_station");
+        assert_eq!(Query::new(&subway, "from /(/ to B").err().unwrap().code(), "invalid_pattern");
+        assert!(Query::new(&subway, "disable /^C$/").unwrap().is_disable());
+
+        let alias = Query::new(&subway, "alias Bee Station = b");
+        assert!(alias.as_ref().unwrap().is_alias());
+        assert!(alias.as_ref().unwrap().is_mutation());
+        assert!(!route.is_mutation());
+        if let Ok(Query::Alias(name, stn)) = alias {
+            assert_eq!((name.as_slice(), stn), ("Bee Station", b_id));
+        }
+        assert_eq!(Query::new(&subway, "alias X = D").err().unwrap().code(), "no_such_station");
+    }
+
+    #[test]
+    fn test_new_scheduled() {
+        let mut subway = Subway::new();
+        let a_id = subway.add_station("A");
+        let b_id = subway.add_station("B");
+
+        match Query::new(&subway, "from A to B depart at 2015-01-24 08:00") {
+            Ok(Query::RouteAt(from, to, at)) => {
+                assert_eq!((from, to), (a_id, b_id));
+                assert_eq!(at, parse_time("2015-01-24 08:00").unwrap());
+            },
+            q => panic!("unexpected {:?}", q),
+        }
+        assert_eq!(Query::new(&subway, "from A to B depart at noon").err().unwrap().code(),
+                   "invalid_time");
+
+        match Query::new(&subway, "schedule segment A to B from 2015-01-24 05:00 \
+                                   until 2015-01-26 03:00") {
+            Ok(Query::Schedule(d)) => {
+                assert_eq!(d.closure, Closure::Segment(a_id, b_id));
+                assert_eq!(d.end, parse_time("2015-01-26 03:00").unwrap());
+            },
+            q => panic!("unexpected {:?}", q),
+        }
+        assert!(Query::new(&subway, "schedule branch Mattapan from 2015-01-24 05:00 \
+                                     until 2015-01-26 03:00").unwrap().is_mutation());
+        match Query::new(&subway, "unschedule 2") {
+            Ok(Query::Unschedule(n)) => assert_eq!(n, 2),
+            q => panic!("unexpected {:?}", q),
+        }
+        match Query::new(&subway, "unschedule line red from 2015-01-24 05:00 \
+                                   until 2015-01-26 03:00") {
+            Ok(Query::UnscheduleDisruption(d)) => {
+                assert_eq!(d.closure, Closure::Line("red".to_string()));
+            },
+            q => panic!("unexpected {:?}", q),
+        }
+        let list = Query::new(&subway, "schedule").unwrap();
+        assert!(!list.is_mutation() && !list.is_route());
+        assert!(!Query::new(&subway, "status").unwrap().is_mutation());
+    }
+
+    #[test]
+    fn test_new_closures() {
+        let mut subway = Subway::new();
+        let a_id = subway.add_station("A");
+        let b_id = subway.add_station("B");
+
+        match Query::new(&subway, "disable segment A to B") {
+            Ok(Query::DisableSegment(from, to)) => assert_eq!((from, to), (a_id, b_id)),
+            q => panic!("unexpected {:?}", q),
+        }
+        match Query::new(&subway, "enable segment b to a") {
+            Ok(Query::EnableSegment(from, to)) => assert_eq!((from, to), (b_id, a_id)),
+            q => panic!("unexpected {:?}", q),
+        }
+        match Query::new(&subway, "disable branch Mattapan") {
+            Ok(Query::DisableBranch(branch)) => assert_eq!(branch.as_slice(), "Mattapan"),
+            q => panic!("unexpected {:?}", q),
+        }
+        match Query::new(&subway, "enable line green") {
+            Ok(Query::EnableLine(line)) => assert_eq!(line.as_slice(), "green"),
+            q => panic!("unexpected {:?}", q),
+        }
+        match Query::new(&subway, "pass through A") {
+            Ok(Query::PassThrough(stn)) => assert_eq!(stn, a_id),
+            q => panic!("unexpected {:?}", q),
+        }
+        match Query::new(&subway, "stop at B") {
+            Ok(Query::StopAt(stn)) => assert_eq!(stn, b_id),
+            q => panic!("unexpected {:?}", q),
+        }
+        match Query::new(&subway, "disable A, b") {
+            Ok(Query::DisableStations(stns, None)) => assert_eq!(stns, vec![a_id, b_id]),
+            q => panic!("unexpected {:?}", q),
+        }
+        match Query::new(&subway, "disable B for incident 42") {
+            Ok(Query::DisableStations(stns, Some(incident))) => {
+                assert_eq!(stns, vec![b_id]);
+                assert_eq!(incident.as_slice(), "42");
+            },
+            q => panic!("unexpected {:?}", q),
+        }
+        match Query::new(&subway, "enable incident 42") {
+            Ok(Query::EnableIncident(incident)) => assert_eq!(incident.as_slice(), "42"),
+            q => panic!("unexpected {:?}", q),
+        }
+        assert_eq!(Query::new(&subway, "disable A, C").err().unwrap().code(), "no_such_station");
+        let h_id = subway.add_station("Harvard Square, Cambridge");
+        match Query::new(&subway, "disable Harvard Square, Cambridge") {
+            Ok(Query::Disable(stn)) => assert_eq!(stn, h_id),
+            q => panic!("unexpected {:?}", q),
+        }
+        match Query::new(&subway, "disable Harvard Square, Cambridge for incident 7") {
+            Ok(Query::DisableStations(stns, _)) => assert_eq!(stns, vec![h_id]),
+            q => panic!("unexpected {:?}", q),
+        }
+        assert_eq!(Query::new(&subway, "disable segment A to C").err().unwrap().code(),
+                   "no_such_station");
+        assert!(Query::new(&subway, "disable line red").unwrap().is_mutation());
     }
 }
 
 
-/// Attempts to find a route from `start` to `end`
-pub fn find_route(graph: &Subway, start: StationId, end: StationId) -> Result<String, String> {
-    if let Some(path_ids) = find_path(graph, start, end) {
-        return Ok(build_path_string(graph, path_ids));
+/// One ride along a single line & branch, from boarding to alighting.
+#[derive(Clone, Show, PartialEq)]
+pub struct Leg {
+    pub info: StationInfo,
+    pub board: StationId,
+    pub alight: StationId,
+    /// Stations passed through between `board` and `alight`, in order.
+    pub stops: Vec<StationId>,
+}
+
+impl Leg {
+    /// The connection ridden from `from` to `to` along the leg, preferring one on the
+    /// leg's own branch to one on the trunk the train also runs along.
+    pub fn connection<'a>(&self, graph: &'a Subway, from: StationId, to: StationId)
+                          -> Option<&'a Connection> {
+        graph.get_connections(from).and_then(|cs| {
+            cs.iter().find(|c| c.to == to && c.info == self.info)
+              .or_else(|| cs.iter().find(|c| c.to == to && same_train(&c.info, &self.info)))
+        })
     }
-    Err(format!("No path from {} to {}", start, end))
 }
 
+/// A route from one station to another, as found by `find_route`.
+#[derive(Clone, Show, PartialEq)]
+pub struct Route {
+    pub legs: Vec<Leg>,
+    pub cost: usize,
+    pub transfers: usize,
+    /// Disabled stations the route passes through, in order.
+    pub disabled: Vec<StationId>,
+    /// Estimated travel time in minutes, if every segment of the route is timed.
+    pub minutes: Option<usize>,
+}
 
+impl Route {
+    /// Builds a `Route` out of a path as returned by `find_path`.
+    fn from_path(graph: &Subway, closed: &Closures, cost: usize,
+                 path: Vec<(StationId, StationInfo)>) -> Route {
+        let mut legs: Vec<Leg> = vec![];
+        for (i, &(from, ref info)) in path.iter().enumerate() {
+            let to: StationId = match path.get(i + 1) {
+                Some(&(id, _)) => id,
+                None => break,
+            };
+            // staying aboard from the trunk onto a branch, or back, is no transfer
+            let same_leg: bool = match legs.last() {
+                Some(leg) => same_train(&leg.info, info),
+                None => false,
+            };
+            if same_leg {
+                let leg = legs.last_mut().unwrap();
+                let passed: StationId = leg.alight;
+                leg.stops.push(passed);
+                leg.alight = to;
+                // riders are told the branch the train has to be on
+                if leg.info != *info && runs_onto(&leg.info, info) { leg.info = info.clone(); }
+            } else {
+                legs.push(Leg { info: info.clone(), board: from, alight: to, stops: vec![] });
+            }
+        }
 
-fn build_path_string(graph: &Subway, path_ids: Vec<(StationId, StationInfo)>) -> String {
-    let mut path_string: String = String::new();
-    let mut prev_line: String = String::new();
-    let mut prev_branch: String = String::new();
-    for &(ref id, ref info) in path_ids.iter() {
-        if let Some(stn) = graph.get_station(*id) {
-            // TODO: below seems brittle AF, must be DRYer way
-            if prev_line.is_empty() && prev_branch.is_empty() {
-                prev_line = info.line.to_string();
-                prev_branch = info.branch.to_string();
+        let mut disabled: Vec<StationId> = vec![];
+        for &(id, _) in path.iter() {
+            if closed.stations.contains(&id) && !disabled.contains(&id) {
+                disabled.push(id);
             }
-            if prev_branch.as_slice() != info.branch.as_slice() {
-                if info.branch.as_slice() != info.line.as_slice() {
-                    for &s in ["---ensure you are on ", info.branch.as_slice(), "\n"].iter() {
-                        path_string.push_str(s);
-                    }
-                }
+        }
+
+        let transfers: usize = if legs.is_empty() { 0 } else { legs.len() - 1 };
+        let minutes = travel_time(graph, &legs);
+        Route { legs: legs, cost: cost, transfers: transfers, disabled: disabled,
+                minutes: minutes }
+    }
+
+    /// Returns every station along the route in order, transfer stations once.
+    pub fn stations(&self) -> Vec<StationId> {
+        let mut stations: Vec<StationId> = vec![];
+        for leg in self.legs.iter() {
+            if stations.is_empty() { stations.push(leg.board); }
+            stations.push_all(leg.stops.as_slice());
+            stations.push(leg.alight);
+        }
+        stations
+    }
+}
+
+/// Sums the minutes of every segment along `legs` and of walking between platforms
+/// wherever the line changes. `None` if any segment is untimed.
+fn travel_time(graph: &Subway, legs: &Vec<Leg>) -> Option<usize> {
+    let mut minutes: usize = 0;
+    let mut prev_leg: Option<&Leg> = None;
+    for leg in legs.iter() {
+        if let Some(prev) = prev_leg {
+            if prev.info.line != leg.info.line {
+                minutes += graph.transfers.get(&leg.board).map(|t| t.walk).unwrap_or(0);
             }
-            if prev_line.as_slice() != info.line.as_slice() {
-                let strs = ["---switch from ",
-                            prev_line.as_slice(),
-                            " to ",
-                            info.line.as_slice(),
-                            "\n"];
-                for &s in strs.iter() { path_string.push_str(s); }
+        }
+        let mut stations: Vec<StationId> = vec![leg.board];
+        stations.push_all(leg.stops.as_slice());
+        stations.push(leg.alight);
+        for pair in stations.windows(2) {
+            match leg.connection(graph, pair[0], pair[1]).and_then(|c| c.minutes) {
+                Some(m) => minutes += m,
+                None => return None,
             }
+        }
+        prev_leg = Some(leg);
+    }
+    Some(minutes)
+}
 
-            if info.branch.as_slice() == info.line.as_slice() {
-                for &s in [stn.as_slice(), ", take ", info.line.as_slice(), "\n"].iter() {
-                    path_string.push_str(s);
-                }
-            } else {
-                for &s in [stn.as_slice(), ", take ", info.branch.as_slice(), "\n"].iter() {
-                    path_string.push_str(s);
-                }
+/// Attempts to find a route from `start` to `end`, as of now by `graph`'s clock.
+pub fn find_route(graph: &Subway, start: StationId, end: StationId)
+                  -> Result<Route, TQueryError> {
+    find_route_at(graph, start, end, graph.clock.now())
+}
+
+/// Attempts to find a route from `start` to `end` for a rider setting off at `at`, with
+/// whatever is scheduled to be closed then closed.
+pub fn find_route_at(graph: &Subway, start: StationId, end: StationId, at: Timespec)
+                     -> Result<Route, TQueryError> {
+    let closed = Closures::at(graph, at);
+    if let DisabledPolicy::Penalize(_) = graph.disabled_policy {} else {
+        for &stn in [start, end].iter() {
+            if closed.stations.contains(&stn) {
+                return Err(TQueryError::DisabledStation { station: stn });
             }
-            prev_line = info.line.to_string();
-            prev_branch = info.branch.to_string();
         }
     }
+    if let Some((cost, path_ids)) = search(graph, &closed, start, end) {
+        return Ok(Route::from_path(graph, &closed, cost, path_ids));
+    }
+    Err(TQueryError::NoPath { from: start, to: end })
+}
+
+/// What a rider should know before taking `route`, such as the disabled stations on it.
+pub fn route_warnings(graph: &Subway, route: &Route) -> Vec<String> {
+    route.disabled.iter().map(|stn| {
+        let name: &str = graph.get_station(*stn).map(|s| s.as_slice()).unwrap_or("?");
+        format!("this route passes through disabled station {}", name)
+    }).collect()
+}
+
+/// Renders `route` as directions for a rider, one line per station.
+pub fn format_route(graph: &Subway, route: &Route) -> String {
+    let mut path_string: String = String::new();
+    for warning in route_warnings(graph, route).iter() {
+        path_string.push_str(format!("---warning: {}\n", warning).as_slice());
+    }
+    let mut prev_info: Option<&StationInfo> = None;
+    for leg in route.legs.iter() {
+        let info: &StationInfo = &leg.info;
+        if info.line.as_slice() == WALK_LINE {
+            let mut stations: Vec<StationId> = vec![leg.board];
+            stations.push_all(leg.stops.as_slice());
+            stations.push(leg.alight);
+            for pair in stations.windows(2) {
+                path_string.push_str(format!("{}, walk to {}\n", station_name(graph, pair[0]),
+                                             station_name(graph, pair[1])).as_slice());
+            }
+            prev_info = Some(info);
+            continue;
+        }
+        if let Some(prev) = prev_info {
+            if prev.branch != info.branch && info.branch != info.line {
+                path_string.push_str(format!("---ensure you are on {}\n", info.branch).as_slice());
+            }
+            if prev.line != info.line && prev.line.as_slice() != WALK_LINE {
+                path_string.push_str(format!("---switch from {} to {}\n",
+                                             prev.line, info.line).as_slice());
+            }
+        }
+        path_string.push_str(take_line(graph, route, leg.board, info).as_slice());
+        for stop in leg.stops.iter() {
+            path_string.push_str(take_line(graph, route, *stop, info).as_slice());
+        }
+        prev_info = Some(info);
+    }
+    // the last walk already names where it ends
+    match route.legs.last() {
+        Some(leg) if leg.info.line.as_slice() != WALK_LINE => {
+            path_string.push_str(take_line(graph, route, leg.alight, &leg.info).as_slice());
+        },
+        _ => {},
+    }
+    if let Some(minutes) = route.minutes {
+        path_string.push_str(format!("estimated travel time: {} min\n", minutes).as_slice());
+    }
     return path_string;
 }
 
-#[derive(Copy, Eq, PartialEq)]
+fn station_name(graph: &Subway, stn_id: StationId) -> String {
+    graph.get_station(stn_id).map(|s| s.clone()).unwrap_or("?".to_string())
+}
+
+fn take_line(graph: &Subway, route: &Route, stn_id: StationId, info: &StationInfo) -> String {
+    let mut stn: String = station_name(graph, stn_id);
+    if no_stop(graph, stn_id, route.disabled.contains(&stn_id)) { stn.push_str(" (no stop)"); }
+    if info.branch == info.line {
+        format!("{}, take {}\n", stn, info.line)
+    } else {
+        format!("{}, take {}\n", stn, info.branch)
+    }
+}
+
+/// Extra cost charged for changing subway lines.
+const LINE_TRANSFER_COST: usize = 3;
+/// Extra cost charged for changing branches on the same line.
+const BRANCH_TRANSFER_COST: usize = 2;
+
+/// A node of the search space: a station together with the line & branch the rider
+/// arrived on. The info is `None` only for the starting station.
+type Node = (StationId, Option<StationInfo>);
+
+#[derive(Eq, PartialEq)]
 struct State {
     cost: usize,
-    position: usize,
+    node: Node,
 }
 
 impl Ord for State {
     fn cmp(&self, other: &State) -> Ordering {
-        other.cost.cmp(&self.cost)
+        // flipped for a min-heap, ties broken on the node so search order is deterministic
+        match other.cost.cmp(&self.cost) {
+            Ordering::Equal => other.node.cmp(&self.node),
+            ord => ord,
+        }
     }
 }
 
@@ -195,70 +679,149 @@ impl PartialOrd for State {
     }
 }
 
+/// Cost of boarding a connection on `next` at station `at` having arrived on `prev`.
+fn transfer_cost(graph: &Subway, at: StationId, prev: &Option<StationInfo>,
+                 next: &StationInfo) -> usize {
+    match *prev {
+        // Line transfers considered heaviest cost, unless the interchange says otherwise
+        Some(ref p) if p.line != next.line => match graph.transfers.get(&at) {
+            Some(t) => t.cost.unwrap_or(LINE_TRANSFER_COST) + t.walk,
+            None => LINE_TRANSFER_COST,
+        },
+        // branch transfers not as heavy
+        Some(ref p) if p.branch != next.branch => BRANCH_TRANSFER_COST,
+        _ => 0,
+    }
+}
+
+/// Whether a rider arriving on `prev` stays aboard when carrying on along `next`: the
+/// same line, and the same branch or the trunk running onto one of its branches.
+fn same_train(prev: &StationInfo, next: &StationInfo) -> bool {
+    prev.line == next.line &&
+        (prev.branch == next.branch || runs_onto(prev, next) || runs_onto(next, prev))
+}
+
+/// Whether `a` is a trunk running onto branch `b`, i.e. named after the line or after
+/// several branches including `b`.
+fn runs_onto(a: &StationInfo, b: &StationInfo) -> bool {
+    a.branch == a.line || a.branch.as_slice().words().any(|w| w == b.branch.as_slice())
+}
+
+/// Whether riders can neither board nor alight at station `stn_id`, given whether it is
+/// disabled.
+fn no_stop(graph: &Subway, stn_id: StationId, disabled: bool) -> bool {
+    graph.pass_through_stations.contains(&stn_id) ||
+        (disabled && graph.disabled_policy == DisabledPolicy::PassThrough)
+}
+
 /// Implmentation of Dijkstra's algorithm to find the shortest path.
 /// based on implementation in Rust documentation:
 /// http://doc.rust-lang.org/1.0.0-alpha/collections/binary_heap/index.html
+///
+/// The search runs over (station, line & branch arrived on) pairs rather than bare
+/// stations, so a transfer penalty is charged exactly once for every change of line or
+/// branch along the path that is actually built.
 pub fn find_path(graph: &Subway, start: StationId, end: StationId)
 				 -> Option<Vec<(StationId, StationInfo)>> {
+    find_path_at(graph, start, end, graph.clock.now())
+}
+
+/// Like `find_path`, but as of `at` rather than now.
+pub fn find_path_at(graph: &Subway, start: StationId, end: StationId, at: Timespec)
+                    -> Option<Vec<(StationId, StationInfo)>> {
+    search(graph, &Closures::at(graph, at), start, end).map(|(_, path)| path)
+}
+
+/// Does the work of `find_path`, with everything in `closed` closed, also returning the
+/// total cost of the path found.
+fn search(graph: &Subway, closed: &Closures, start: StationId, end: StationId)
+          -> Option<(usize, Vec<(StationId, StationInfo)>)> {
+
+    // riders can neither board nor alight where trains do not stop
+    for &stn in [start, end].iter() {
+        if no_stop(graph, stn, closed.stations.contains(&stn)) { return None; }
+    }
 
     // dist[node] = current shortest distance from `start` to `node`
-    let mut dist: Vec<_> = range(0, graph.size()).map(|_| uint::MAX).collect();
+    let mut dist: HashMap<Node, usize> = HashMap::new();
     let mut heap = BinaryHeap::new();
-    let mut came_from: HashMap<StationId, (StationId, StationInfo)> = HashMap::new();
-    let mut prev_stn: Option<StationInfo> = None;
+    let mut came_from: HashMap<Node, Node> = HashMap::new();
 
-    // We're at `start`, with a zero cost
-    dist[start] = 0;
-    heap.push(State { cost: 0, position: start });
+    // We're at `start`, with a zero cost and not on any line yet
+    let start_node: Node = (start, None);
+    dist.insert(start_node.clone(), 0);
+    heap.push(State { cost: 0, node: start_node });
 
     // Examine the frontier with lower cost nodes first (min-heap)
-    while let Some(State { cost, position: current }) = heap.pop() {
-        if current == end { return Some(reconstruct_path(came_from, end)); }
+    while let Some(State { cost, node }) = heap.pop() {
+        let current: StationId = node.0;
+        if current == end { return Some((cost, reconstruct_path(&came_from, node))); }
 
-        if cost > dist[current] { continue; }
+        if cost > *dist.get(&node).unwrap_or(&uint::MAX) { continue; }
+
+        let connections = match graph.get_connections(current) {
+            Some(cs) => cs,
+            None => continue,
+        };
 
         // For each node we can reach, see if we can find a way with
         // a lower cost going through this node
-        let connections = graph.get_connections(current).unwrap();
         for connection in connections.iter() {
+            if !closed.is_open(current, connection) { continue; }
+            // no changing trains where trains do not stop
+            if no_stop(graph, current, closed.stations.contains(&current)) {
+                if let Some(ref prev) = node.1 {
+                    if !same_train(prev, &connection.info) { continue; }
+                }
+            }
+
             let mut c: usize = connection.cost;
-            if !connection.active  { c += DISABLE_COST; }
-            if let Some(prev_info) = prev_stn {
-                let prev_line = prev_info.line.as_slice();
-                let prev_branch = prev_info.branch.as_slice();
-                let cur_line = connection.info.line.as_slice();
-                let cur_branch = connection.info.branch.as_slice();
-                // Line transfers considered heaviest cost
-                if prev_line != cur_line { c = 3; }
-                // branch transfers not as heavy
-                else if  prev_branch != cur_branch { c = 2; }
+            if closed.stations.contains(&current) || closed.stations.contains(&connection.to) {
+                match graph.disabled_policy {
+                    DisabledPolicy::Forbid => continue,
+                    DisabledPolicy::Penalize(cost) => c += cost,
+                    DisabledPolicy::PassThrough => {},
+                }
             }
-            prev_stn = Some(connection.info.clone());
-            let next = State { cost: cost + c, position: connection.to };
+            c += transfer_cost(graph, current, &node.1, &connection.info);
+
+            let next: Node = (connection.to, Some(connection.info.clone()));
+            let next_cost: usize = cost + c;
 
-            if next.cost < dist[next.position] {
-                heap.push(next);
-                dist[next.position] = next.cost;
-                came_from.insert(next.position, (current, connection.info.clone()));
+            if next_cost < *dist.get(&next).unwrap_or(&uint::MAX) {
+                dist.insert(next.clone(), next_cost);
+                came_from.insert(next.clone(), node.clone());
+                heap.push(State { cost: next_cost, node: next });
             }
         }
     }
     None
 }
 
-/// Retrace steps to build the path that was found as a list of nodes
-fn reconstruct_path(came_from: HashMap<StationId, (StationId, StationInfo)>, goal: StationId)
+/// Retrace steps to build the path that was found as a list of stations, each paired
+/// with the line & branch taken out of it. The goal is paired with the line it was
+/// reached on.
+fn reconstruct_path(came_from: &HashMap<Node, Node>, goal: Node)
 					-> Vec<(StationId, StationInfo)> {
 
+    let mut nodes: Vec<Node> = vec![goal.clone()];
+    let mut current: Option<&Node> = came_from.get(&goal);
+    while let Some(node) = current {
+        nodes.push(node.clone());
+        current = came_from.get(node);
+    }
+    nodes.reverse();
+
     let mut total_path: Vec<(StationId, StationInfo)> = vec![];
-    let &(_, ref i) = came_from.get(&goal).unwrap();
-    total_path.push((goal, i.clone()));
-    let mut current: Option<&(StationId, StationInfo)> = came_from.get(&goal);
-    while let Some(&(ref id, ref info)) = current {
-        total_path.push((*id, info.clone()));
-        current = came_from.get(id);
-    }
-    total_path.reverse();
+    for (i, node) in nodes.iter().enumerate() {
+        let leaving: &Option<StationInfo> = match nodes.get(i + 1) {
+            Some(next) => &next.1,
+            None => &node.1,
+        };
+        if let Some(ref info) = *leaving {
+            total_path.push((node.0, info.clone()));
+        }
+    }
     return total_path;
 }
 
@@ -286,3 +849,298 @@ fn test_find_path() {
     assert_eq!(route, vec![0, 3]);
 }
 
+#[test]
+fn test_find_path_charges_transfers_once() {
+    let mut subway = Subway::new();
+    let a_id = subway.add_station("A");
+    let b_id = subway.add_station("B");
+    let c_id = subway.add_station("C");
+    let d_id = subway.add_station("D");
+
+    for &(f, t) in [(a_id, b_id), (b_id, c_id), (c_id, d_id)].iter() {
+        subway.add_connection(f, t, "x", "x");
+        subway.add_connection(t, f, "x", "x");
+    }
+    // shorter in stops, but needs a line change at B
+    subway.add_connection(b_id, d_id, "y", "y");
+    subway.add_connection(d_id, b_id, "y", "y");
+
+    let route = find_path(&subway, a_id, d_id).unwrap();
+    let stops: Vec<StationId> = route.iter().map(|&(id, _)| id).collect();
+    assert_eq!(stops, vec![a_id, b_id, c_id, d_id]);
+    assert!(route.iter().all(|&(_, ref info)| info.line.as_slice() == "x"));
+}
+
+#[test]
+fn test_find_path_prefers_staying_on_branch() {
+    let mut subway = Subway::new();
+    let a_id = subway.add_station("A");
+    let b_id = subway.add_station("B");
+    let c_id = subway.add_station("C");
+
+    subway.add_connection(a_id, b_id, "x", "1");
+    subway.add_connection(b_id, c_id, "x", "2");
+    subway.add_connection(b_id, c_id, "x", "1");
+
+    let route = find_path(&subway, a_id, c_id).unwrap();
+    assert_eq!(route.len(), 3);
+    assert!(route.iter().all(|&(_, ref info)| info.branch.as_slice() == "1"));
+}
+
+#[test]
+fn test_find_path_closures() {
+    let mut subway = Subway::new();
+    let a_id = subway.add_station("A");
+    let b_id = subway.add_station("B");
+    let c_id = subway.add_station("C");
+    let d_id = subway.add_station("D");
+    let e_id = subway.add_station("E");
+
+    for &(f, t, b) in [(a_id, b_id, "red"), (b_id, c_id, "Braintree"),
+                       (b_id, d_id, "Mattapan")].iter() {
+        subway.add_connection(f, t, "red", b);
+        subway.add_connection(t, f, "red", b);
+    }
+    // the long way round
+    for &(f, t) in [(a_id, e_id), (e_id, d_id)].iter() {
+        subway.add_timed_connection(f, t, "y", "y", Some(5));
+        subway.add_timed_connection(t, f, "y", "y", Some(5));
+    }
+    let stops = |&: subway: &Subway| -> Vec<StationId> {
+        find_path(subway, a_id, d_id).unwrap().iter().map(|&(id, _)| id).collect()
+    };
+    assert_eq!(stops(&subway), vec![a_id, b_id, d_id]);
+
+    subway.disable_branch("mattapan").unwrap();
+    assert_eq!(stops(&subway), vec![a_id, e_id, d_id]);
+    subway.enable_branch("Mattapan").unwrap();
+    assert_eq!(stops(&subway), vec![a_id, b_id, d_id]);
+
+    subway.disable_line("RED").unwrap();
+    assert_eq!(stops(&subway), vec![a_id, e_id, d_id]);
+    subway.enable_line("red").unwrap();
+
+    subway.disable_segment(d_id, b_id).unwrap();
+    assert_eq!(stops(&subway), vec![a_id, e_id, d_id]);
+    subway.enable_segment(b_id, d_id).unwrap();
+    assert_eq!(stops(&subway), vec![a_id, b_id, d_id]);
+
+    // trains still run through B, but nobody gets on or off there
+    subway.pass_through_station(b_id);
+    assert_eq!(stops(&subway), vec![a_id, b_id, d_id]);
+    assert!(find_path(&subway, a_id, b_id).is_none());
+    assert!(find_path(&subway, b_id, c_id).is_none());
+    let route = find_route(&subway, a_id, d_id).unwrap();
+    assert!(format_route(&subway, &route).contains("B (no stop), take Mattapan\n"));
+    subway.stop_at_station(b_id);
+    assert!(find_path(&subway, a_id, b_id).is_some());
+}
+
+#[test]
+fn test_find_path_pass_through_needs_same_train() {
+    let mut subway = Subway::new();
+    let a_id = subway.add_station("A");
+    let b_id = subway.add_station("B");
+    let c_id = subway.add_station("C");
+    let d_id = subway.add_station("D");
+
+    subway.add_connection(a_id, b_id, "x", "x");
+    subway.add_connection(b_id, c_id, "y", "y");
+    subway.add_connection(a_id, d_id, "x", "x");
+    subway.add_timed_connection(d_id, c_id, "y", "y", Some(9));
+
+    assert_eq!(find_path(&subway, a_id, c_id).unwrap()[1].0, b_id);
+    // changing lines at B is no longer possible
+    subway.pass_through_station(b_id);
+    assert_eq!(find_path(&subway, a_id, c_id).unwrap()[1].0, d_id);
+}
+
+#[test]
+fn test_same_train() {
+    let info = |&: line: &str, branch: &str| StationInfo { line: line.to_string(),
+                                                          branch: branch.to_string() };
+    assert!(same_train(&info("red", "red"), &info("red", "Mattapan")));
+    assert!(same_train(&info("green", "B C D"), &info("green", "C")));
+    assert!(same_train(&info("green", "C"), &info("green", "B C D")));
+    assert!(!same_train(&info("green", "C"), &info("green", "D")));
+    assert!(!same_train(&info("red", "red"), &info("orange", "orange")));
+}
+
+#[test]
+fn test_find_route() {
+    let mut subway = Subway::new();
+    let a_id = subway.add_station("A");
+    let b_id = subway.add_station("B");
+    let c_id = subway.add_station("C");
+    let d_id = subway.add_station("D");
+
+    subway.add_connection(a_id, b_id, "x", "x");
+    subway.add_connection(b_id, c_id, "x", "x");
+    subway.add_connection(c_id, d_id, "y", "y");
+    subway.disable_station(b_id);
+    subway.disabled_policy = DisabledPolicy::Penalize(DISABLE_COST);
+
+    let route = find_route(&subway, a_id, d_id).unwrap();
+    assert_eq!(route.legs.len(), 2);
+    assert_eq!(route.transfers, 1);
+    assert_eq!(route.legs[0].board, a_id);
+    assert_eq!(route.legs[0].stops, vec![b_id]);
+    assert_eq!(route.legs[0].alight, c_id);
+    assert_eq!(route.legs[1].info.line.as_slice(), "y");
+    assert_eq!(route.disabled, vec![b_id]);
+    assert_eq!(route.stations(), vec![a_id, b_id, c_id, d_id]);
+
+    let directions = format_route(&subway, &route);
+    assert_eq!(directions.as_slice(),
+               "---warning: this route passes through disabled station B\n\
+                A, take x\nB, take x\n---switch from x to y\nC, take y\nD, take y\n");
+    assert_eq!(route.minutes, None);
+}
+
+#[test]
+fn test_disabled_policy() {
+    let mut subway = Subway::new();
+    let a_id = subway.add_station("A");
+    let b_id = subway.add_station("B");
+    let c_id = subway.add_station("C");
+    let d_id = subway.add_station("D");
+
+    for &(f, t, l) in [(a_id, b_id, "x"), (b_id, c_id, "x"), (b_id, d_id, "y")].iter() {
+        subway.add_connection(f, t, l, l);
+        subway.add_connection(t, f, l, l);
+    }
+    // the long way round
+    subway.add_timed_connection(a_id, c_id, "z", "z", Some(50));
+    subway.disable_station(b_id);
+
+    // forbidden by default
+    assert_eq!(find_route(&subway, a_id, c_id).unwrap().legs[0].info.line.as_slice(), "z");
+    assert_eq!(find_route(&subway, a_id, d_id).unwrap_err().code(), "no_path");
+    assert_eq!(find_route(&subway, a_id, b_id).unwrap_err(),
+               TQueryError::DisabledStation { station: b_id });
+
+    subway.disabled_policy = DisabledPolicy::Penalize(10);
+    let route = find_route(&subway, a_id, c_id).unwrap();
+    assert_eq!(route.stations(), vec![a_id, b_id, c_id]);
+    assert_eq!(route.disabled, vec![b_id]);
+    assert_eq!(route_warnings(&subway, &route),
+               vec!["this route passes through disabled station B".to_string()]);
+    assert!(find_route(&subway, a_id, b_id).is_ok());
+    subway.disabled_policy = DisabledPolicy::Penalize(100);
+    assert_eq!(find_route(&subway, a_id, c_id).unwrap().legs[0].info.line.as_slice(), "z");
+
+    // trains keep running through B, but no changing lines there
+    subway.disabled_policy = DisabledPolicy::PassThrough;
+    let route = find_route(&subway, a_id, c_id).unwrap();
+    assert_eq!(route.stations(), vec![a_id, b_id, c_id]);
+    assert!(format_route(&subway, &route).starts_with("---warning: this route passes through \
+                                                       disabled station B\n"));
+    assert!(format_route(&subway, &route).contains("B (no stop), take x\n"));
+    assert_eq!(find_route(&subway, a_id, d_id).unwrap_err().code(), "no_path");
+    assert_eq!(find_route(&subway, b_id, c_id).unwrap_err().code(), "disabled_station");
+}
+
+#[test]
+fn test_disabled_policy_from_str() {
+    assert_eq!("forbid".parse::<DisabledPolicy>(), Some(DisabledPolicy::Forbid));
+    assert_eq!("penalize".parse::<DisabledPolicy>(), Some(DisabledPolicy::Penalize(DISABLE_COST)));
+    assert_eq!("penalize:7".parse::<DisabledPolicy>(), Some(DisabledPolicy::Penalize(7)));
+    assert_eq!("pass-through".parse::<DisabledPolicy>(), Some(DisabledPolicy::PassThrough));
+    assert_eq!("penalize:".parse::<DisabledPolicy>(), None);
+    assert_eq!("allow".parse::<DisabledPolicy>(), None);
+}
+
+#[test]
+fn test_find_route_scheduled() {
+    let mut subway = Subway::new();
+    let a_id = subway.add_station("A");
+    let b_id = subway.add_station("B");
+    let c_id = subway.add_station("C");
+
+    subway.add_connection(a_id, b_id, "x", "x");
+    subway.add_connection(b_id, c_id, "x", "x");
+    subway.add_timed_connection(a_id, c_id, "z", "z", Some(50));
+    let weekend = |&: closure: Closure| Disruption {
+        closure: closure,
+        start: parse_time("2015-01-24 05:00").unwrap(),
+        end: parse_time("2015-01-26 03:00").unwrap(),
+    };
+    subway.add_disruption(weekend(Closure::Segment(c_id, b_id))).unwrap();
+    subway.add_disruption(weekend(Closure::Station(b_id))).unwrap();
+    assert_eq!(subway.add_disruption(weekend(Closure::Segment(a_id, a_id))).unwrap_err().code(),
+               "no_such_segment");
+
+    subway.clock = Box::new(FixedClock(parse_time("2015-01-23 17:00").unwrap()));
+    assert_eq!(find_route(&subway, a_id, c_id).unwrap().stations(), vec![a_id, b_id, c_id]);
+    assert!(find_path(&subway, a_id, b_id).is_some());
+
+    let saturday = parse_time("2015-01-24 12:00").unwrap();
+    assert_eq!(find_route_at(&subway, a_id, c_id, saturday).unwrap().stations(),
+               vec![a_id, c_id]);
+    assert_eq!(find_route_at(&subway, a_id, b_id, saturday).unwrap_err().code(),
+               "disabled_station");
+    assert!(find_path_at(&subway, a_id, b_id, saturday).is_none());
+
+    // the clock reaches the weekend
+    subway.clock = Box::new(FixedClock(saturday));
+    assert_eq!(find_route(&subway, a_id, c_id).unwrap().stations(), vec![a_id, c_id]);
+    assert_eq!(subway.remove_disruption(3).unwrap_err().code(), "invalid_choice");
+    assert_eq!(subway.remove_disruption(2).unwrap().closure, Closure::Station(b_id));
+    assert!(find_path(&subway, a_id, b_id).is_some());
+}
+
+#[test]
+fn test_find_route_travel_time() {
+    let mut subway = Subway::new();
+    let a_id = subway.add_station("A");
+    let b_id = subway.add_station("B");
+    let c_id = subway.add_station("C");
+
+    subway.add_timed_connection(a_id, b_id, "x", "x", Some(3));
+    subway.add_timed_connection(b_id, c_id, "y", "y", Some(4));
+    subway.add_transfer(b_id, b_id, None, 2);
+
+    let route = find_route(&subway, a_id, c_id).unwrap();
+    assert_eq!(route.minutes, Some(9));
+    assert!(format_route(&subway, &route).ends_with("estimated travel time: 9 min\n"));
+}
+
+#[test]
+fn test_format_route_walking() {
+    let mut subway = Subway::new();
+    let a_id = subway.add_station("A");
+    let b_id = subway.add_station("B");
+    let c_id = subway.add_station("C");
+    let d_id = subway.add_station("D");
+    subway.add_connection(a_id, b_id, "x", "x");
+    subway.add_timed_connection(b_id, c_id, WALK_LINE, WALK_LINE, Some(4));
+    subway.add_connection(c_id, d_id, "y", "y");
+
+    let route = find_route(&subway, a_id, d_id).unwrap();
+    assert_eq!(format_route(&subway, &route).as_slice(),
+               "A, take x\nB, walk to C\nC, take y\nD, take y\n");
+    let route = find_route(&subway, a_id, c_id).unwrap();
+    assert_eq!(format_route(&subway, &route).as_slice(), "A, take x\nB, walk to C\n");
+    assert_eq!(subway.lines(), vec!["x".to_string(), "y".to_string()]);
+}
+
+#[test]
+fn test_find_route_same_train() {
+    let mut subway = Subway::new();
+    let a_id = subway.add_station("A");
+    let b_id = subway.add_station("B");
+    let c_id = subway.add_station("C");
+    subway.add_timed_connection(a_id, b_id, "red", "red", Some(2));
+    subway.add_timed_connection(b_id, c_id, "red", "Mattapan", Some(3));
+
+    // riding on from the trunk onto the branch is one leg, on the branch's train
+    let route = find_route(&subway, a_id, c_id).unwrap();
+    assert_eq!(route.transfers, 0);
+    assert_eq!(route.legs.len(), 1);
+    assert_eq!(route.legs[0].info.branch.as_slice(), "Mattapan");
+    assert_eq!(route.legs[0].stops, vec![b_id]);
+    assert_eq!(route.minutes, Some(5));
+    assert_eq!(format_route(&subway, &route).as_slice(),
+               "A, take Mattapan\nB, take Mattapan\nC, take Mattapan\n\
+                estimated travel time: 5 min\n");
+}
@@ -0,0 +1,229 @@
+//! Disruptions planned ahead of time, such as weekend closures, each closing a station,
+//! segment, branch or line for a window of time. Routes are found as of a moment in time,
+//! now by default, with whatever is scheduled to be closed then closed along with
+//! anything closed by hand.
+//!
+//! Times are written `YYYY-MM-DD HH:MM`, in UTC.
+#![allow(unstable)]
+
+use std::cmp;
+use std::collections::HashSet;
+
+use time;
+use time::Timespec;
+
+use error::TQueryError;
+
+use super::{Subway, StationId, StationInfo, Connection};
+
+/// Tells the time, so routes can be found as of now.
+pub trait Clock {
+    fn now(&self) -> Timespec;
+}
+
+/// The system's clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timespec { time::get_time() }
+}
+
+/// A clock stopped at the time it holds, for tests.
+pub struct FixedClock(pub Timespec);
+
+impl Clock for FixedClock {
+    fn now(&self) -> Timespec { self.0 }
+}
+
+/// What a disruption closes.
+#[derive(Clone, PartialEq, Show)]
+pub enum Closure {
+    Station(StationId),
+    /// The segment between two adjacent stations, in both directions
+    Segment(StationId, StationId),
+    /// Every branch by the given name
+    Branch(String),
+    Line(String),
+}
+
+/// A closure in effect from `start` until, but not including, `end`.
+#[derive(Clone, PartialEq, Show)]
+pub struct Disruption {
+    pub closure: Closure,
+    pub start: Timespec,
+    pub end: Timespec,
+}
+
+impl Disruption {
+    /// Whether the disruption is in effect at `at`.
+    pub fn is_active(&self, at: Timespec) -> bool {
+        self.start <= at && at < self.end
+    }
+}
+
+/// Everything closed at one moment, by hand or by schedule.
+pub struct Closures {
+    pub stations: HashSet<StationId>,
+    /// Pairs of adjacent stations, lowest id first
+    pub segments: HashSet<(StationId, StationId)>,
+    pub branches: HashSet<StationInfo>,
+    pub lines: HashSet<String>,
+}
+
+impl Closures {
+    /// What is closed in `subway` at `at`.
+    pub fn at(subway: &Subway, at: Timespec) -> Closures {
+        let mut closed = Closures {
+            stations: subway.disabled_stations.clone(),
+            segments: subway.closed_segments.clone(),
+            branches: subway.closed_branches.clone(),
+            lines: subway.closed_lines.clone(),
+        };
+        for disruption in subway.schedule.iter().filter(|d| d.is_active(at)) {
+            match disruption.closure {
+                Closure::Station(stn) => { closed.stations.insert(stn); },
+                Closure::Segment(a, b) => {
+                    closed.segments.insert((cmp::min(a, b), cmp::max(a, b)));
+                },
+                Closure::Branch(ref branch) => {
+                    let infos = subway.find_branches(branch.as_slice()).unwrap_or(vec![]);
+                    for info in infos.into_iter() { closed.branches.insert(info); }
+                },
+                Closure::Line(ref line) => {
+                    if let Ok(line) = subway.find_line(line.as_slice()) {
+                        closed.lines.insert(line);
+                    }
+                },
+            }
+        }
+        closed
+    }
+
+    /// Whether trains run along `connection` out of station `from`.
+    pub fn is_open(&self, from: StationId, connection: &Connection) -> bool {
+        let segment = (cmp::min(from, connection.to), cmp::max(from, connection.to));
+        !self.segments.contains(&segment) &&
+            !self.branches.contains(&connection.info) &&
+            !self.lines.contains(&connection.info.line)
+    }
+}
+
+/// Parses a time written `YYYY-MM-DD HH:MM`, in UTC.
+pub fn parse_time(s: &str) -> Result<Timespec, TQueryError> {
+    match time::strptime(s.trim(), "%Y-%m-%d %H:%M") {
+        Ok(tm) => Ok(tm.to_timespec()),
+        Err(_) => Err(TQueryError::InvalidTime { time: s.trim().to_string(),
+                                                 reason: "expected YYYY-MM-DD HH:MM, in UTC"
+                                                         .to_string() }),
+    }
+}
+
+/// Writes `at` the way `parse_time` reads it.
+pub fn format_time(at: Timespec) -> String {
+    let tm = time::at_utc(at);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday,
+            tm.tm_hour, tm.tm_min)
+}
+
+/// Parses what a disruption closes: `station STATION`, `segment STATION to STATION`,
+/// `branch NAME` or `line NAME`, looking stations up with `find`.
+pub fn parse_closure<F>(target: &str, find: &F) -> Result<Closure, TQueryError>
+                        where F: Fn(&str) -> Result<StationId, TQueryError> {
+    let target: &str = target.trim();
+    let (kind, rest) = match target.find(' ') {
+        Some(space) => (target.slice_to(space), target.slice_from(space + 1).trim()),
+        None => return Err(TQueryError::ParseQuery { query: target.to_string() }),
+    };
+    match kind {
+        "station" => (*find)(rest).map(Closure::Station),
+        "segment" => match rest.find_str(" to ") {
+            Some(to) => {
+                let from_id = try!((*find)(rest.slice_to(to).trim()));
+                let to_id = try!((*find)(rest.slice_from(to + " to ".len()).trim()));
+                Ok(Closure::Segment(from_id, to_id))
+            },
+            None => Err(TQueryError::ParseQuery { query: target.to_string() }),
+        },
+        "branch" => Ok(Closure::Branch(rest.to_string())),
+        "line" => Ok(Closure::Line(rest.to_string())),
+        _ => Err(TQueryError::ParseQuery { query: target.to_string() }),
+    }
+}
+
+/// `disruption` written out with station names, as `schedule` lists it.
+pub fn describe_disruption(subway: &Subway, disruption: &Disruption) -> String {
+    let name = |&: id: StationId| -> &str {
+        subway.get_station(id).map(|s| s.as_slice()).unwrap_or("?")
+    };
+    let closure: String = match disruption.closure {
+        Closure::Station(stn) => format!("station {}", name(stn)),
+        Closure::Segment(a, b) => format!("segment {} to {}", name(a), name(b)),
+        Closure::Branch(ref branch) => format!("branch {}", branch),
+        Closure::Line(ref line) => format!("line {}", line),
+    };
+    format!("{} from {} until {}", closure, format_time(disruption.start),
+            format_time(disruption.end))
+}
+
+#[cfg(test)]
+mod schedule_tests {
+    use time::Timespec;
+
+    use super::{Closure, Closures, Disruption, parse_time, format_time, parse_closure};
+    use super::describe_disruption;
+    use subway::Subway;
+
+    #[test]
+    fn test_parse_time() {
+        let at: Timespec = parse_time("2015-01-24 05:30").unwrap();
+        assert_eq!(at.sec, 1422077400);
+        assert_eq!(format_time(at).as_slice(), "2015-01-24 05:30");
+        assert_eq!(parse_time("saturday").unwrap_err().code(), "invalid_time");
+    }
+
+    #[test]
+    fn test_parse_closure() {
+        let mut subway = Subway::new();
+        let a_id = subway.add_station("A");
+        let b_id = subway.add_station("B");
+        let find = |&: stn: &str| subway.find_station(stn);
+        assert_eq!(parse_closure("station a", &find), Ok(Closure::Station(a_id)));
+        assert_eq!(parse_closure("segment A to B", &find), Ok(Closure::Segment(a_id, b_id)));
+        assert_eq!(parse_closure("branch Mattapan", &find),
+                   Ok(Closure::Branch("Mattapan".to_string())));
+        assert_eq!(parse_closure("line red", &find), Ok(Closure::Line("red".to_string())));
+        assert_eq!(parse_closure("segment A", &find).unwrap_err().code(), "parse_query");
+        assert_eq!(parse_closure("station C", &find).unwrap_err().code(), "no_such_station");
+        assert_eq!(parse_closure("platform A", &find).unwrap_err().code(), "parse_query");
+    }
+
+    #[test]
+    fn test_closures_at() {
+        let mut subway = Subway::new();
+        let a_id = subway.add_station("A");
+        let b_id = subway.add_station("B");
+        subway.add_connection(a_id, b_id, "red", "Mattapan");
+        subway.disable_station(a_id);
+        let weekend = |&: closure: Closure| Disruption {
+            closure: closure,
+            start: parse_time("2015-01-24 05:00").unwrap(),
+            end: parse_time("2015-01-26 03:00").unwrap(),
+        };
+        subway.schedule.push(weekend(Closure::Station(b_id)));
+        subway.schedule.push(weekend(Closure::Branch("mattapan".to_string())));
+        assert_eq!(describe_disruption(&subway, &subway.schedule[0]).as_slice(),
+                   "station B from 2015-01-24 05:00 until 2015-01-26 03:00");
+
+        let before = Closures::at(&subway, parse_time("2015-01-24 04:59").unwrap());
+        assert_eq!(before.stations.len(), 1);
+        assert!(before.is_open(a_id, subway.get_connection(a_id, b_id).unwrap()));
+
+        let during = Closures::at(&subway, parse_time("2015-01-25 12:00").unwrap());
+        assert!(during.stations.contains(&a_id) && during.stations.contains(&b_id));
+        assert!(!during.is_open(a_id, subway.get_connection(a_id, b_id).unwrap()));
+
+        let after = Closures::at(&subway, parse_time("2015-01-26 03:00").unwrap());
+        assert!(!after.stations.contains(&b_id));
+        assert!(after.branches.is_empty());
+    }
+}